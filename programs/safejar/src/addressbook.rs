@@ -0,0 +1,139 @@
+use anchor_lang;
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+
+use crate::errors::TreasuryError;
+use crate::{
+    AddressBookAdd, AddressBookRemove, CloseAddressBook, CreateAddressBook, ID,
+    PROGRAM_ADDRESS_BOOK_SEED,
+};
+
+/// keeps the account under the 10KB limit for accounts created in an instruction
+pub const ADDRESS_BOOK_MAX_CAPACITY: u16 = 300;
+
+/// A list of payees and blocked destinations kept by the controller owner.
+/// Rules only commit to the address of the book, so entries can change without
+/// re-creating delegations.
+#[account]
+pub struct AddressBook {
+    pub bump: u8,
+    pub controller: Pubkey,
+    pub book_id: u32,
+    pub capacity: u16,
+    pub entries: Vec<AddressBookEntry>,
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AddressBookEntry {
+    // a token account or the owner of token accounts
+    pub address: Pubkey,
+    pub status: AddressBookStatus,
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressBookStatus {
+    Allowed,
+    Blocked,
+}
+
+impl AddressBook {
+    pub fn init(
+        &mut self,
+        bump: u8,
+        controller: &Pubkey,
+        book_id: u32,
+        capacity: u16,
+    ) -> Result<()> {
+        if capacity == 0 || ADDRESS_BOOK_MAX_CAPACITY < capacity {
+            return Err(TreasuryError::AddressBookBadCapacity.into());
+        }
+        self.bump = bump;
+        self.controller = *controller;
+        self.book_id = book_id;
+        self.capacity = capacity;
+        self.entries = Vec::new();
+        Ok(())
+    }
+
+    pub fn lookup(&self, address: &Pubkey) -> Option<AddressBookStatus> {
+        self.entries
+            .iter()
+            .find(|x| x.address == *address)
+            .map(|x| x.status)
+    }
+
+    // an existing entry is retagged rather than duplicated
+    pub fn set(&mut self, address: &Pubkey, status: AddressBookStatus) -> Result<()> {
+        if let Some(entry) = self.entries.iter_mut().find(|x| x.address == *address) {
+            entry.status = status;
+            return Ok(());
+        }
+        if self.capacity as usize <= self.entries.len() {
+            return Err(TreasuryError::AddressBookFull.into());
+        }
+        self.entries.push(AddressBookEntry {
+            address: *address,
+            status,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, address: &Pubkey) -> Result<()> {
+        match self.entries.iter().position(|x| x.address == *address) {
+            Some(i) => {
+                self.entries.swap_remove(i);
+                Ok(())
+            }
+            None => Err(TreasuryError::AddressBookEntryNotFound.into()),
+        }
+    }
+}
+
+impl<'info> CreateAddressBook<'info> {
+    pub fn process(&mut self, bump: u8, book_id: u32, capacity: u16) -> ProgramResult {
+        self.book
+            .init(bump, &self.controller.key(), book_id, capacity)?;
+        Ok(())
+    }
+}
+
+impl<'info> AddressBookAdd<'info> {
+    pub fn process(&mut self, address: Pubkey, allowed: bool) -> ProgramResult {
+        let status = if allowed {
+            AddressBookStatus::Allowed
+        } else {
+            AddressBookStatus::Blocked
+        };
+        self.book.set(&address, status)?;
+        Ok(())
+    }
+}
+
+impl<'info> AddressBookRemove<'info> {
+    pub fn process(&mut self, address: Pubkey) -> ProgramResult {
+        self.book.remove(&address)?;
+        Ok(())
+    }
+}
+
+impl<'info> CloseAddressBook<'info> {
+    // rules that point at a closed book fail until a book is created again at the same address
+    pub fn process(&mut self) -> ProgramResult {
+        Ok(())
+    }
+}
+
+pub(crate) fn address_book_account_size(capacity: u16) -> usize {
+    8 + std::mem::size_of::<AddressBook>()
+        + (capacity as usize) * std::mem::size_of::<AddressBookEntry>()
+}
+
+pub fn address_book_id(controller: &Pubkey, book_id: u32) -> Pubkey {
+    let x = [
+        PROGRAM_ADDRESS_BOOK_SEED,
+        controller.as_ref(),
+        &book_id.to_le_bytes(),
+    ];
+    let (ans, _bump) = Pubkey::find_program_address(&x, &ID);
+    ans
+}
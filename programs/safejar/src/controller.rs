@@ -61,6 +61,7 @@ impl<'info> CloseControllerVault<'info> {
 }
 
 impl<'info> TransferToController<'info> {
+    // no status check; the controller owner can always pull funds back, including from revoked delegations
     pub fn process(&mut self, amount: u64) -> ProgramResult {
         //msg!("delete me later - 1");
         //msg!("transfering amount={}",amount);
@@ -146,6 +147,7 @@ impl<'info> TransferToController<'info> {
 
 impl<'info> TransferToDelegation<'info> {
     pub fn process(&mut self, amount: u64) -> ProgramResult {
+        self.delegation.check_fundable(Clock::get()?.slot)?;
         if self.controller_vault.mint == sol_mint {
             token::sync_native(CpiContext::new(
                 self.token_program.to_account_info(),
@@ -1,13 +1,19 @@
 use crate::errors::TreasuryError;
-use crate::spend::SpendState;
+use crate::spend::{
+    delegation_account_size, SpendRecord, SpendState, SpendStateSlot, SPEND_HISTORY_SIZE,
+    SPEND_REQUEST_EXPIRY_SLOTS,
+};
 use crate::{
-    nplog, ApproveDelegation, CloseDelegation, Delegate, RejectDelegation, ID,
+    nplog, ApproveDelegation, CloseDelegation, Delegate, MigrateDelegation, PauseDelegation,
+    RejectDelegation, ResumeDelegation, RevokeDelegation, SetMaxRequestAge, ID,
     PROGRAM_DELEGATION_SEED,
 };
 use anchor_lang;
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::entrypoint::ProgramResult;
 use anchor_lang::solana_program::hash::HASH_BYTES;
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
 
 #[account]
 pub struct Delegation {
@@ -17,10 +23,99 @@ pub struct Delegation {
     pub rule_set_hash: [u8; 32],
     pub state: SpendState,
     pub requested_slot: u64,
+    // rule hash format used to build rule_set_hash; see rule::RULE_HASH_V0
+    pub hash_version: u8,
+    // read this through status(), which accounts for expiry
+    pub status: DelegationStatus,
+    // slots a spend request stays completable; read this through max_request_age()
+    pub max_request_age: u64,
+}
+
+/// number of slots the controller owner has to approve a delegation
+pub const DELEGATION_APPROVAL_WINDOW: u64 = 1000;
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DelegationStatus {
+    // created, waiting for the controller owner to approve
+    Pending,
+    // funds can be spent
+    Active,
+    // temporarily frozen by the controller owner
+    Paused,
+    // permanently frozen by the controller owner; funds can only go back to the controller
+    Revoked,
+    // never written; this is how Pending reads once DELEGATION_APPROVAL_WINDOW has passed
+    Expired,
 }
 
 // controller at offset=8+1
 // rule_set_hash at offset=8+1+32+8
+// hash_version, status and max_request_age were appended; delegations created before them
+// read as RULE_HASH_V0, Pending (see status() for how those are treated) and 0
+
+/// Delegation as it was stored before SpendStateSlot gained history and total_spent.
+/// Those accounts no longer deserialize as Delegation until migrate_delegation rewrites them.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct LegacyDelegation {
+    pub bump: u8,
+    pub controller: Pubkey,
+    pub rule_set_count: u8,
+    pub rule_set_hash: [u8; 32],
+    // SpendState.list
+    pub state: Vec<LegacySpendStateSlot>,
+    pub requested_slot: u64,
+    pub hash_version: u8,
+    pub status: DelegationStatus,
+    pub max_request_age: u64,
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct LegacySpendStateSlot {
+    pub mint: Pubkey,
+    pub index: u64,
+    pub last_spend: u64,
+    pub last_slot: u64,
+    pub generic_score: u8,
+}
+
+impl LegacyDelegation {
+    /// The same delegation in the current layout.
+    /// Only the last spend is known, so that is all the rate limiting history gets;
+    /// total_spent starts counting from zero.
+    pub fn upgrade(&self) -> Delegation {
+        let list = self
+            .state
+            .iter()
+            .map(|x| {
+                let mut history = [SpendRecord::default(); SPEND_HISTORY_SIZE];
+                history[0] = SpendRecord {
+                    slot: x.last_slot,
+                    amount: x.last_spend,
+                };
+                SpendStateSlot {
+                    mint: x.mint,
+                    index: x.index,
+                    last_spend: x.last_spend,
+                    last_slot: x.last_slot,
+                    generic_score: x.generic_score,
+                    history,
+                    total_spent: 0,
+                }
+            })
+            .collect();
+        Delegation {
+            bump: self.bump,
+            controller: self.controller,
+            rule_set_count: self.rule_set_count,
+            rule_set_hash: self.rule_set_hash,
+            state: SpendState { list },
+            requested_slot: self.requested_slot,
+            hash_version: self.hash_version,
+            status: self.status,
+            max_request_age: self.max_request_age,
+        }
+    }
+}
 
 impl<'a> Delegation {
     pub fn init(
@@ -49,9 +144,98 @@ impl<'a> Delegation {
         nplog!("setting spend state max to {}", max_spend_state);
         self.state = SpendState::new(max_spend_state);
         self.requested_slot = slot;
+        self.status = DelegationStatus::Pending;
+        self.max_request_age = 0;
         nplog!("delegate - 3");
         Ok(())
     }
+
+    pub fn status(&self, slot: u64) -> DelegationStatus {
+        if self.status == DelegationStatus::Pending && self.requested_slot == 0 {
+            // approved before the status field existed; approval used to zero requested_slot
+            return DelegationStatus::Active;
+        }
+        if self.status == DelegationStatus::Pending
+            && self.requested_slot + DELEGATION_APPROVAL_WINDOW < slot
+        {
+            return DelegationStatus::Expired;
+        }
+        self.status
+    }
+
+    /// Slots after creation during which a spend request can be completed.
+    /// Zero means SPEND_REQUEST_EXPIRY_SLOTS.
+    pub fn max_request_age(&self) -> u64 {
+        if self.max_request_age == 0 {
+            return SPEND_REQUEST_EXPIRY_SLOTS;
+        }
+        self.max_request_age
+    }
+
+    /// Call this before moving funds out of the delegation to anyone but the controller,
+    /// or between its own token accounts.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is not active.
+    pub fn check_spendable(&self, slot: u64) -> Result<()> {
+        match self.status(slot) {
+            DelegationStatus::Active => Ok(()),
+            x => Err(status_error(x).into()),
+        }
+    }
+
+    /// Funds can be moved into the delegation as long as it has not been revoked or expired.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is revoked or expired.
+    pub fn check_fundable(&self, slot: u64) -> Result<()> {
+        match self.status(slot) {
+            x @ (DelegationStatus::Revoked | DelegationStatus::Expired) => {
+                Err(status_error(x).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Move the delegation to a new status.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the transition is not allowed from the current status.
+    pub fn set_status(&mut self, next: DelegationStatus, slot: u64) -> Result<()> {
+        let current = self.status(slot);
+        let allowed = matches!(
+            (current, next),
+            (DelegationStatus::Pending, DelegationStatus::Active)
+                | (DelegationStatus::Active, DelegationStatus::Paused)
+                | (DelegationStatus::Paused, DelegationStatus::Active)
+                | (DelegationStatus::Pending, DelegationStatus::Revoked)
+                | (DelegationStatus::Active, DelegationStatus::Revoked)
+                | (DelegationStatus::Paused, DelegationStatus::Revoked)
+                | (DelegationStatus::Expired, DelegationStatus::Revoked)
+        );
+        if !allowed {
+            nplog!("status transition {:?} -> {:?}", current, next);
+            if current == DelegationStatus::Expired || current == DelegationStatus::Revoked {
+                return Err(status_error(current).into());
+            }
+            return Err(TreasuryError::DelegationBadStatusTransition.into());
+        }
+        self.status = next;
+        Ok(())
+    }
+}
+
+fn status_error(status: DelegationStatus) -> TreasuryError {
+    match status {
+        DelegationStatus::Pending => TreasuryError::DelegationPending,
+        DelegationStatus::Paused => TreasuryError::DelegationPaused,
+        DelegationStatus::Revoked => TreasuryError::DelegationRevoked,
+        DelegationStatus::Expired => TreasuryError::DelegationExpired,
+        DelegationStatus::Active => TreasuryError::DelegationBadStatusTransition,
+    }
 }
 
 impl<'info> Delegate<'info> {
@@ -68,6 +252,7 @@ impl<'info> Delegate<'info> {
             self.clock.slot,
             max_spend_state,
         )?;
+        self.delegation.hash_version = self.accumulator.version;
 
         nplog!("delegate - 3");
         Ok(())
@@ -76,7 +261,85 @@ impl<'info> Delegate<'info> {
 
 impl<'info> ApproveDelegation<'info> {
     pub fn process(&mut self) -> ProgramResult {
-        self.delegation.requested_slot = 0;
+        let slot = Clock::get()?.slot;
+        self.delegation.set_status(DelegationStatus::Active, slot)?;
+
+        Ok(())
+    }
+}
+
+impl<'info> PauseDelegation<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let slot = Clock::get()?.slot;
+        self.delegation.set_status(DelegationStatus::Paused, slot)?;
+
+        Ok(())
+    }
+}
+
+impl<'info> ResumeDelegation<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let slot = Clock::get()?.slot;
+        self.delegation.set_status(DelegationStatus::Active, slot)?;
+
+        Ok(())
+    }
+}
+
+impl<'info> RevokeDelegation<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let slot = Clock::get()?.slot;
+        self.delegation
+            .set_status(DelegationStatus::Revoked, slot)?;
+
+        Ok(())
+    }
+}
+
+impl<'info> SetMaxRequestAge<'info> {
+    pub fn process(&mut self, max_request_age: u64) -> ProgramResult {
+        // zero goes back to the default
+        self.delegation.max_request_age = max_request_age;
+        Ok(())
+    }
+}
+
+impl<'info> MigrateDelegation<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let info = self.delegation.to_account_info();
+        let delegation = {
+            let data = info.try_borrow_data()?;
+            if data.len() < 8 || data[..8] != Delegation::DISCRIMINATOR {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if Delegation::try_deserialize(&mut &data[..]).is_ok() {
+                return Err(ProgramError::Custom(
+                    TreasuryError::DelegationAlreadyMigrated.into(),
+                ));
+            }
+            LegacyDelegation::deserialize(&mut &data[8..])?.upgrade()
+        };
+
+        // the payer covers the rent for the larger spend state
+        let size = delegation_account_size(delegation.state.list.len() as u8);
+        let rent = Rent::get()?
+            .minimum_balance(size)
+            .saturating_sub(info.lamports());
+        if 0 < rent {
+            system_program::transfer(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: self.payer.to_account_info(),
+                        to: info.clone(),
+                    },
+                ),
+                rent,
+            )?;
+        }
+        info.realloc(size, true)?;
+        let mut data = info.try_borrow_mut_data()?;
+        delegation.try_serialize(&mut &mut data[..])?;
 
         Ok(())
     }
@@ -44,5 +44,90 @@ pub enum TreasuryError {
     RuleMaxBalanceExceeded,
     #[msg("insufficient amount")]
     BalanceInsufficient,
-    
+    #[msg("unknown rule hash version")]
+    RuleHashVersionUnknown,
+    #[msg("delegation has not been approved")]
+    DelegationPending,
+    #[msg("delegation is paused")]
+    DelegationPaused,
+    #[msg("delegation has been revoked")]
+    DelegationRevoked,
+    #[msg("delegation was not approved in time")]
+    DelegationExpired,
+    #[msg("delegation status does not allow this change")]
+    DelegationBadStatusTransition,
+    #[msg("rule time window ends before it starts")]
+    RuleTimeWindowEndsBeforeStart,
+    #[msg("rule time window has not started")]
+    RuleTimeWindowNotStarted,
+    #[msg("rule time window has ended")]
+    RuleTimeWindowEnded,
+    #[msg("rule budget cap cannot be zero")]
+    RuleBudgetCapCannotBeZero,
+    #[msg("rule budget cap mint does not match")]
+    RuleBudgetCapWrongMint,
+    #[msg("rule budget cap exceeded")]
+    RuleBudgetCapExceeded,
+    #[msg("rule amount constraint max cannot be zero")]
+    RuleAmountConstraintCannotBeZero,
+    #[msg("rule amount constraint mint does not match")]
+    RuleAmountConstraintWrongMint,
+    #[msg("rule amount constraint max exceeded")]
+    RuleAmountConstraintExceeded,
+    #[msg("rule allowlist proof is too long")]
+    RuleAllowlistProofTooLong,
+    #[msg("rule allowlist destination does not match the spend request")]
+    RuleAllowlistWrongDestination,
+    #[msg("rule allowlist does not include the destination")]
+    RuleAllowlistDestinationNotAllowed,
+    #[msg("address book capacity is zero or too large")]
+    AddressBookBadCapacity,
+    #[msg("address book is full")]
+    AddressBookFull,
+    #[msg("address book entry not found")]
+    AddressBookEntryNotFound,
+    #[msg("rule address book destination does not match the spend request")]
+    RuleAddressBookWrongDestination,
+    #[msg("rule address book has blocked the destination")]
+    RuleAddressBookBlocked,
+    #[msg("rule address book does not list the destination")]
+    RuleAddressBookNotListed,
+    #[msg("rule multisig has too many authorizers")]
+    RuleMultisigTooManyAuthorizers,
+    #[msg("rule multisig threshold must be between 1 and the number of authorizers")]
+    RuleMultisigBadThreshold,
+    #[msg("rule multisig lists an authorizer twice")]
+    RuleMultisigDuplicateAuthorizer,
+    #[msg("rule multisig does not have enough signers")]
+    RuleMultisigNotEnoughSigners,
+    #[msg("approval does not match the spend request amount or destination")]
+    SpendRequestApprovalMismatch,
+    #[msg("rule ed25519 signature over the spend intent not found")]
+    RuleEd25519SignatureMissing,
+    #[msg("rule secp256k1 signature over the spend intent not found")]
+    RuleSecp256k1SignatureMissing,
+    #[msg("spend direct needs another remaining account for its rule parameters")]
+    SpendDirectMissingAccount,
+    #[msg("spend direct remaining account is not the one its rule parameter needs")]
+    SpendDirectWrongAccount,
+    #[msg("spend direct rule parameters do not match the delegation rule set")]
+    SpendDirectRuleSetMismatch,
+    #[msg("only the linker, or the controller owner once the request expires, can cancel it")]
+    SpendRequestCancelNotAllowed,
+    #[msg("spend request has not expired")]
+    SpendRequestNotExpired,
+    #[msg("spend request has expired")]
+    SpendRequestExpired,
+    #[msg("a balance checked by a rule changed before the spend request was completed")]
+    SpendRequestBalanceChanged,
+    #[msg("spend request has too many balance checks")]
+    SpendRequestTooManyBalanceChecks,
+    #[msg("rule caller program did not make this call")]
+    RuleCallerProgramMismatch,
+    #[msg("rule accumulator does not have every rule of its tree yet")]
+    RuleAccumulatorIncomplete,
+    #[msg("only the linker or the controller owner can abort a rule accumulator")]
+    RuleAccumulatorAbortNotAllowed,
+    #[msg("delegation is already in the current layout")]
+    DelegationAlreadyMigrated,
 }
@@ -47,6 +47,8 @@ impl<'info> ConsolidateVault<'info>{
         return Ok(())
     }
     pub fn process(&mut self)->ProgramResult{
+        // only active delegations, as when approval was requested_slot==0
+        self.delegation.check_spendable(Clock::get()?.slot)?;
         let delegation_ai = self.delegation.to_account_info();
         let destination_ai=self.ata_vault.to_account_info();
         let bump_vector = self.delegation.bump.to_le_bytes();
@@ -1,3 +1,9 @@
+//! Treasury program: a controller delegates funds under a tree of spending rules.
+//!
+//! Other programs pay out of a delegation through `safejar::cpi`, which Anchor generates
+//! with the `cpi` feature. The PDA spend requests and the wrappers around those calls
+//! are in spendcpi.
+
 use std::cmp::Ordering;
 
 use anchor_lang::{prelude::*, solana_program::entrypoint::ProgramResult};
@@ -13,6 +19,7 @@ use anchor_spl::{
 
 
 pub mod controller;
+pub mod addressbook;
 pub mod delegate;
 pub mod rule;
 pub mod ruleratelimiter;
@@ -20,10 +27,22 @@ pub mod ruleprogconstr;
 pub mod ruleauthconstr;
 pub mod rulesweep;
 pub mod rulemaxbal;
+pub mod ruletimewindow;
+pub mod rulebudget;
+pub mod rulemaxamt;
+pub mod ruleallowlist;
+pub mod ruleaddrbook;
+pub mod rulemultisig;
+pub mod ruleed25519;
+pub mod rulesecp256k1;
+pub mod rulecaller;
 pub mod spend;
+pub mod spenddirect;
+pub mod spendcpi;
 pub mod extra;
 pub mod errors;
 pub mod tree;
+pub mod sigverify;
 pub mod sol;
 pub mod log;
 
@@ -31,9 +50,12 @@ pub mod log;
 
 
 use controller::Controller;
-use delegate::Delegation;
+use addressbook::{AddressBook, address_book_account_size};
+use delegate::{Delegation, DelegationStatus};
 use rule::RuleAccumulator;
-use spend::{SpendRequest, delegation_account_size};
+use spend::{SpendReceipt, SpendRequest, delegation_account_size, spend_request_account_size};
+use spenddirect::RuleParam;
+use errors::TreasuryError;
 
 
 declare_id!("TRSY7YgS3tcDoi6ZgTp2MmPJpXHyCVrGaFhL7HLdQc9");
@@ -99,16 +121,75 @@ pub mod safejar {
         return ctx.accounts.process(amount);
     }
 
-    /// .
+    /// Create address book book_id of the controller, with room for capacity entries.
     ///
     /// # Errors
     ///
-    /// This function will return an error if .
+    /// This function will return an error if capacity is zero or above ADDRESS_BOOK_MAX_CAPACITY.
+    pub fn create_address_book(
+        ctx: Context<CreateAddressBook>,
+        book_id: u32,
+        capacity: u16,
+    )->ProgramResult{
+        ctx.accounts.process(ctx.bumps.book,book_id,capacity)
+    }
+
+    /// Mark address as allowed or blocked in the book, adding it if it is not there yet.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the book is full.
+    pub fn address_book_add(
+        ctx: Context<AddressBookAdd>,
+        address: Pubkey,
+        allowed: bool,
+    )->ProgramResult{
+        ctx.accounts.process(address,allowed)
+    }
+
+    /// Take address out of the book.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if address is not in the book.
+    pub fn address_book_remove(
+        ctx: Context<AddressBookRemove>,
+        address: Pubkey,
+    )->ProgramResult{
+        ctx.accounts.process(address)
+    }
+
+    /// Close the book and refund its rent to the controller owner.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the signer is not the controller owner.
+    pub fn close_address_book(ctx: Context<CloseAddressBook>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// Start collecting the rules of tree, in an accumulator at a PDA of the controller and nonce.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the tree is not valid.
     pub fn create_rule_accumulator(
         ctx: Context<CreateRuleAccumulator>,
         tree: Vec<u8>,
+        nonce: u64,
     )->ProgramResult{
-        return ctx.accounts.process(tree);
+        ctx.accounts.process(ctx.bumps.accumulator,tree,nonce)
+    }
+
+    /// Close an accumulator that will not be delegated and refund its rent to the linker.
+    /// The linker can abort at any time, and so can the controller owner.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the signer is neither the linker nor the controller
+    /// owner.
+    pub fn abort_accumulator(ctx: Context<AbortAccumulator>)->ProgramResult{
+        ctx.accounts.process()
     }
 
     /// .
@@ -181,6 +262,121 @@ pub mod safejar {
         return ctx.accounts.process(min_balance);
     }
 
+    /// Add a rule that passes from valid_from until, but not at, valid_until,
+    /// on the clock's unix timestamp.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if valid_until is not after valid_from, or the
+    /// accumulator already has every rule of its tree.
+    pub fn rule_add_time_window(
+        ctx: Context<RuleAddTimeWindow>,
+        valid_from: i64, valid_until: i64,
+    )->ProgramResult{
+        ctx.accounts.process(valid_from,valid_until)
+    }
+
+    /// Add a rule that caps everything the delegation ever spends of mint at max_total.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if max_total is zero, or the accumulator already has
+    /// every rule of its tree.
+    pub fn rule_add_budget_cap(
+        ctx: Context<RuleAddBudgetCap>,
+        max_total: u64,
+    )->ProgramResult{
+        ctx.accounts.process(max_total)
+    }
+
+    /// Add a rule that passes when a single spend of mint is at most max_amount.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if max_amount is zero, or the accumulator already has
+    /// every rule of its tree.
+    pub fn rule_add_amount_constraint(
+        ctx: Context<RuleAddAmountConstraint>,
+        max_amount: u64,
+    )->ProgramResult{
+        ctx.accounts.process(max_amount)
+    }
+
+    /// Add a rule that passes when the destination is in the Merkle tree with this root.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the accumulator already has every rule of its tree.
+    pub fn rule_add_destination_allowlist(
+        ctx: Context<RuleAddDestinationAllowlist>,
+        root: [u8;32],
+    )->ProgramResult{
+        ctx.accounts.process(root)
+    }
+
+    /// Add a rule that passes when the book allows the destination vault or its owner,
+    /// and blocks neither.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the accumulator already has every rule of its tree.
+    pub fn rule_add_address_book(ctx: Context<RuleAddAddressBook>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// Add a rule that passes when threshold of authorizer_list sign.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if threshold is zero or above the number of authorizers,
+    /// authorizer_list is too long or repeats a key, or the accumulator already has every rule of
+    /// its tree.
+    pub fn rule_add_multisig(
+        ctx: Context<RuleAddMultisig>,
+        threshold: u8,
+        authorizer_list: Vec<Pubkey>,
+    )->ProgramResult{
+        ctx.accounts.process(threshold,authorizer_list)
+    }
+
+    /// Add a rule that passes when required_signer signs the spend intent with Ed25519.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the accumulator already has every rule of its tree.
+    pub fn rule_add_ed25519_signature(
+        ctx: Context<RuleAddEd25519Signature>,
+        required_signer: Pubkey,
+    )->ProgramResult{
+        ctx.accounts.process(required_signer)
+    }
+
+    /// Add a rule that passes when the key of eth_address signs the spend intent with Secp256k1.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the accumulator already has every rule of its tree.
+    pub fn rule_add_secp256k1_signature(
+        ctx: Context<RuleAddSecp256k1Signature>,
+        eth_address: [u8;20],
+    )->ProgramResult{
+        ctx.accounts.process(eth_address)
+    }
+
+    /// Require required_program to drive the spend: by CPI when position is
+    /// CALLER_BY_CPI, otherwise with an instruction at that index of the transaction.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the accumulator already has every rule of its tree.
+    pub fn rule_add_caller_program(
+        ctx: Context<RuleAddCallerProgram>,
+        required_program: Pubkey,
+        position: u8,
+    )->ProgramResult{
+        ctx.accounts.process(required_program,position)
+    }
+
 
     /// .
     ///
@@ -211,6 +407,53 @@ pub mod safejar {
         return ctx.accounts.process();
     }
 
+    /// Stop spends from a delegation until the controller owner resumes it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is not active.
+    pub fn pause_delegation(ctx: Context<PauseDelegation>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// Let a paused delegation spend again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is not paused.
+    pub fn resume_delegation(ctx: Context<ResumeDelegation>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// End a delegation for good; a revoked delegation cannot be resumed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is already revoked.
+    pub fn revoke_delegation(ctx: Context<RevokeDelegation>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// Limit how many slots a spend request stays completable after it is created.
+    /// Zero restores the default, SPEND_REQUEST_EXPIRY_SLOTS.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the signer is not the controller owner.
+    pub fn set_max_request_age(ctx: Context<SetMaxRequestAge>,max_request_age: u64)->ProgramResult{
+        ctx.accounts.process(max_request_age)
+    }
+
+    /// Rewrite a delegation created before spend state slots kept a spend history,
+    /// so that it can be read again. Anyone can pay for this.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation is already in the current layout.
+    pub fn migrate_delegation(ctx: Context<MigrateDelegation>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
     /// .
     ///
     /// # Errors
@@ -226,6 +469,64 @@ pub mod safejar {
         return ctx.accounts.process(amount,tree);
     }
 
+    /// Same as create_spend_request_direct, but the request is a PDA of delegation and
+    /// idempotency_key, so that a program can be the linker and create it by CPI.
+    /// A SpendReceipt marks the key as used for good, so a retried invoice cannot be paid twice.
+    /// See spendcpi.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation cannot spend, or idempotency_key
+    /// has already been used, even by a request that was completed since.
+    pub fn create_spend_request_pda(
+        ctx: Context<CreateSpendRequestPda>,
+        amount: u64,
+        tree: Vec<u8>,
+        idempotency_key: [u8;32],
+    )->ProgramResult{
+        ctx.accounts.process(amount,tree,idempotency_key,ctx.bumps.receipt)
+    }
+
+    /// Same as create_spend_request_direct, but destination_owner is owned by a program,
+    /// such as a PDA, so that ProgramConstraint can pin the spend to that program.
+    /// The destination token account must already exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation cannot spend, or destination_owner is a
+    /// system account.
+    pub fn create_spend_request_to_program(
+        ctx: Context<CreateSpendRequestToProgram>,
+        amount: u64,
+        tree: Vec<u8>,
+    )->ProgramResult{
+        ctx.accounts.process(amount,tree)
+    }
+
+    /// Record an approval from an authorizer who cannot sign the linker's transaction.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if amount or destination_vault do not match the request.
+    pub fn approve_spend_request(
+        ctx: Context<ApproveSpendRequest>,
+        amount: u64,
+        destination_vault: Pubkey,
+    )->ProgramResult{
+        ctx.accounts.process(amount,destination_vault)
+    }
+
+    /// Close a spend request that will not be completed and refund its rent to the linker.
+    /// The linker can cancel at any time; the controller owner only once the request has expired.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the authority is neither the linker nor the controller
+    /// owner, or the controller owner cancels before the request expires.
+    pub fn cancel_spend_request(ctx: Context<CancelSpendRequest>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
     /// .
     ///
     /// # Errors
@@ -235,6 +536,22 @@ pub mod safejar {
         return ctx.accounts.process();
     }
 
+    /// Process every rule and transfer in one instruction, without a SpendRequest account.
+    /// param_list has one entry per leaf in rule order; see RuleParam for the remaining accounts.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the delegation cannot spend, param_list or the
+    /// remaining accounts do not match the rule set, or the rules do not pass.
+    pub fn spend_direct<'info>(
+        ctx: Context<'_, '_, '_, 'info, SpendDirect<'info>>,
+        amount: u64,
+        tree: Vec<u8>,
+        param_list: Vec<RuleParam>,
+    )->ProgramResult{
+        ctx.accounts.process(amount,tree,param_list,ctx.remaining_accounts)
+    }
+
     /// .
     ///
     /// # Errors
@@ -318,6 +635,120 @@ pub mod safejar {
         return ctx.accounts.process(min_balance);
     }
 
+    /// Process a time window leaf; completion checks the window again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if valid_until is not after valid_from, or every rule of
+    /// the request has already been processed.
+    pub fn rule_process_time_window(
+        ctx: Context<SpendProcessTimeWindow>,
+        valid_from: i64, valid_until: i64,
+    )->ProgramResult{
+        ctx.accounts.process(valid_from,valid_until)
+    }
+
+    /// Process a budget cap leaf; completion checks it again against the delegation spend state.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if max_total is zero, or every rule of the request has
+    /// already been processed.
+    pub fn rule_process_budget_cap(
+        ctx: Context<SpendProcessBudgetCap>,
+        max_total: u64,
+    )->ProgramResult{
+        ctx.accounts.process(max_total)
+    }
+
+    /// Process an amount constraint leaf against the amount of the request.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if max_amount is zero, or every rule of the request has
+    /// already been processed.
+    pub fn rule_process_amount_constraint(
+        ctx: Context<SpendProcessAmountConstraint>,
+        max_amount: u64,
+    )->ProgramResult{
+        ctx.accounts.process(max_amount)
+    }
+
+    /// Process an allowlist leaf with the Merkle proof of the destination.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the proof is longer than ALLOWLIST_MAX_PROOF, or every
+    /// rule of the request has already been processed.
+    pub fn rule_process_destination_allowlist(
+        ctx: Context<SpendProcessDestinationAllowlist>,
+        root: [u8;32],
+        proof: Vec<[u8;32]>,
+    )->ProgramResult{
+        ctx.accounts.process(root,proof)
+    }
+
+    /// Process an address book leaf against the book as it is now.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if every rule of the request has already been processed.
+    pub fn rule_process_address_book(ctx: Context<SpendProcessAddressBook>)->ProgramResult{
+        ctx.accounts.process()
+    }
+
+    /// Authorizers are passed in as signers in the remaining accounts.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if threshold is zero or above the number of authorizers,
+    /// authorizer_list is too long or repeats a key, or every rule of the request has already been
+    /// processed.
+    pub fn rule_process_multisig<'info>(
+        ctx: Context<'_, '_, '_, 'info, SpendProcessMultisig<'info>>,
+        threshold: u8,
+        authorizer_list: Vec<Pubkey>,
+    )->ProgramResult{
+        ctx.accounts.process(threshold,authorizer_list,ctx.remaining_accounts)
+    }
+
+    /// The Ed25519 program instruction must be in the same transaction.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if every rule of the request has already been processed.
+    pub fn rule_process_ed25519_signature(
+        ctx: Context<SpendProcessEd25519Signature>,
+        required_signer: Pubkey,
+    )->ProgramResult{
+        ctx.accounts.process(required_signer)
+    }
+
+    /// The Secp256k1 program instruction must be in the same transaction.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if every rule of the request has already been processed.
+    pub fn rule_process_secp256k1_signature(
+        ctx: Context<SpendProcessSecp256k1Signature>,
+        eth_address: [u8;20],
+    )->ProgramResult{
+        ctx.accounts.process(eth_address)
+    }
+
+    /// With CALLER_BY_CPI, required_program must make this call by CPI.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if every rule of the request has already been processed.
+    pub fn rule_process_caller_program(
+        ctx: Context<SpendProcessCallerProgram>,
+        required_program: Pubkey,
+        position: u8,
+    )->ProgramResult{
+        ctx.accounts.process(required_program,position)
+    }
+
     /// .
     ///
     /// # Errors
@@ -546,40 +977,37 @@ pub struct TransferToController<'info>{
 
 
 #[derive(Accounts)]
-#[instruction(tree: Box<Vec<u8>>)]
-pub struct CreateRuleAccumulator<'info>{
+#[instruction(book_id: u32, capacity: u16)]
+pub struct CreateAddressBook<'info>{
     #[account(
-        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
         bump=controller.bump,
         constraint=controller.owner==owner.key(),
     )]
     pub controller: Account<'info,Controller>,
 
-    /// CHECK: do not check; controller general funds pays rent
     #[account(
         init,
-        payer = linker,
-        space=8+std::mem::size_of::<RuleAccumulator>(),
+        payer = payer,
+        seeds=[PROGRAM_ADDRESS_BOOK_SEED,controller.key().as_ref(),book_id.to_le_bytes().as_ref()],
+        bump,
+        space=address_book_account_size(capacity),
     )]
-    pub accumulator: Account<'info,RuleAccumulator>,
+    pub book: Box<Account<'info,AddressBook>>,
 
-    
     pub owner: Signer<'info>,
+
     #[account(mut)]
-    pub linker: Signer<'info>,
+    pub payer: Signer<'info>,
 
-    pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    
 }
 
-
 #[derive(Accounts)]
-#[instruction(max_spend: u8, delta_slot: u64)]
-pub struct RuleAddRateLimiter<'info>{
+#[instruction(address: Pubkey, allowed: bool)]
+pub struct AddressBookAdd<'info>{
     #[account(
-        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
         bump=controller.bump,
         constraint=controller.owner==owner.key(),
     )]
@@ -587,23 +1015,19 @@ pub struct RuleAddRateLimiter<'info>{
 
     #[account(
         mut,
-        constraint=accumulator.controller==controller.key(),
-        // run arg constraint check in RateLimiter struct
+        seeds=[PROGRAM_ADDRESS_BOOK_SEED,controller.key().as_ref(),book.book_id.to_le_bytes().as_ref()],
+        bump=book.bump,
     )]
-    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+    pub book: Box<Account<'info,AddressBook>>,
 
     pub owner: Signer<'info>,
-
-    pub mint: Account<'info,Mint>,
-
 }
 
-
 #[derive(Accounts)]
-#[instruction()]
-pub struct RuleAddAuthorizationConstraint<'info>{
+#[instruction(address: Pubkey)]
+pub struct AddressBookRemove<'info>{
     #[account(
-        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
         bump=controller.bump,
         constraint=controller.owner==owner.key(),
     )]
@@ -611,25 +1035,19 @@ pub struct RuleAddAuthorizationConstraint<'info>{
 
     #[account(
         mut,
-        constraint=accumulator.controller==controller.key(),
-        // run arg constraint check in RateLimiter struct
+        seeds=[PROGRAM_ADDRESS_BOOK_SEED,controller.key().as_ref(),book.book_id.to_le_bytes().as_ref()],
+        bump=book.bump,
     )]
-    pub accumulator: Box<Account<'info,RuleAccumulator>>,
-
-    /// CHECK: we just need the public key from the authorizer
-    // the entity that will make spending decisions
-    pub authorizer: AccountInfo<'info>,
+    pub book: Box<Account<'info,AddressBook>>,
 
-    #[account()]
     pub owner: Signer<'info>,
-
 }
 
 #[derive(Accounts)]
 #[instruction()]
-pub struct RuleAddProgramConstraint<'info>{
+pub struct CloseAddressBook<'info>{
     #[account(
-        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
         bump=controller.bump,
         constraint=controller.owner==owner.key(),
     )]
@@ -637,24 +1055,157 @@ pub struct RuleAddProgramConstraint<'info>{
 
     #[account(
         mut,
-        constraint=accumulator.controller==controller.key(),
-        // run arg constraint check in RateLimiter struct
-    )]
-    pub accumulator: Box<Account<'info,RuleAccumulator>>,
-
-    /// CHECK: we just need to check if this is executable and the public key
-    // the owner of the owner of the token account receiving funds
-    #[account(
-        constraint=program.executable
+        close=owner,
+        seeds=[PROGRAM_ADDRESS_BOOK_SEED,controller.key().as_ref(),book.book_id.to_le_bytes().as_ref()],
+        bump=book.bump,
     )]
-    pub program: AccountInfo<'info>,
+    pub book: Box<Account<'info,AddressBook>>,
 
-    #[account()]
+    #[account(mut)]
     pub owner: Signer<'info>,
-
 }
 
-
+#[derive(Accounts)]
+#[instruction(tree: Vec<u8>, nonce: u64)]
+pub struct CreateRuleAccumulator<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    /// CHECK: do not check; controller general funds pays rent
+    #[account(
+        init,
+        payer = linker,
+        space=8+std::mem::size_of::<RuleAccumulator>(),
+        seeds=[PROGRAM_ACCUMULATOR_SEED,controller.key().as_ref(),nonce.to_le_bytes().as_ref()],
+        bump,
+    )]
+    pub accumulator: Account<'info,RuleAccumulator>,
+
+    
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub linker: Signer<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    
+}
+
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct AbortAccumulator<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        close = linker,
+        seeds=[PROGRAM_ACCUMULATOR_SEED,controller.key().as_ref(),accumulator.nonce.to_le_bytes().as_ref()],
+        bump=accumulator.bump,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    /// CHECK: only receives the rent; must be the account that paid it
+    #[account(
+        mut,
+        constraint=accumulator.linker==linker.key(),
+    )]
+    pub linker: AccountInfo<'info>,
+
+    pub authority: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(max_spend: u8, delta_slot: u64)]
+pub struct RuleAddRateLimiter<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in RateLimiter struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+
+    pub mint: Account<'info,Mint>,
+
+}
+
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct RuleAddAuthorizationConstraint<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in RateLimiter struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    /// CHECK: we just need the public key from the authorizer
+    // the entity that will make spending decisions
+    pub authorizer: AccountInfo<'info>,
+
+    #[account()]
+    pub owner: Signer<'info>,
+
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct RuleAddProgramConstraint<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in RateLimiter struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    /// CHECK: we just need to check if this is executable and the public key
+    // the owner of the owner of the token account receiving funds
+    #[account(
+        constraint=program.executable
+    )]
+    pub program: AccountInfo<'info>,
+
+    #[account()]
+    pub owner: Signer<'info>,
+
+}
+
+
 #[derive(Accounts)]
 #[instruction(max_balance: u64)]
 pub struct RuleAddBalanceConstraint<'info>{
@@ -742,6 +1293,190 @@ pub struct RuleAddSweepATA<'info>{
 
 }
 
+#[derive(Accounts)]
+#[instruction(valid_from: i64, valid_until: i64)]
+pub struct RuleAddTimeWindow<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in TimeWindow struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(max_total: u64)]
+pub struct RuleAddBudgetCap<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in BudgetCap struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+
+    pub mint: Account<'info,Mint>,
+}
+
+#[derive(Accounts)]
+#[instruction(max_amount: u64)]
+pub struct RuleAddAmountConstraint<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in AmountConstraint struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+
+    pub mint: Account<'info,Mint>,
+}
+
+#[derive(Accounts)]
+#[instruction(root: [u8;32])]
+pub struct RuleAddDestinationAllowlist<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct RuleAddAddressBook<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+
+    #[account(
+        constraint=book.controller==controller.key(),
+    )]
+    pub book: Box<Account<'info,AddressBook>>,
+}
+
+#[derive(Accounts)]
+#[instruction(threshold: u8, authorizer_list: Vec<Pubkey>)]
+pub struct RuleAddMultisig<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+        // run arg constraint check in MultisigConstraint struct
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(required_signer: Pubkey)]
+pub struct RuleAddEd25519Signature<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(required_program: Pubkey, position: u8)]
+pub struct RuleAddCallerProgram<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(eth_address: [u8;20])]
+pub struct RuleAddSecp256k1Signature<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        constraint=accumulator.controller==controller.key(),
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
+    pub owner: Signer<'info>,
+}
+
 #[derive(Accounts)]
 #[instruction(max_spend_state: u8)]
 pub struct Delegate<'info>{
@@ -754,6 +1489,16 @@ pub struct Delegate<'info>{
     )]
     pub controller: Account<'info,Controller>,
 
+    // every rule in the tree must have been added
+    #[account(
+        mut,
+        close = linker,
+        seeds=[PROGRAM_ACCUMULATOR_SEED,controller.key().as_ref(),accumulator.nonce.to_le_bytes().as_ref()],
+        bump=accumulator.bump,
+        constraint = accumulator.index == accumulator.count @ TreasuryError::RuleAccumulatorIncomplete,
+    )]
+    pub accumulator: Box<Account<'info,RuleAccumulator>>,
+
     #[account(
         init,
         payer = linker,
@@ -766,12 +1511,8 @@ pub struct Delegate<'info>{
 
     #[account(
         mut,
-        close = linker,
-        signer,
+        constraint=accumulator.linker==linker.key(),
     )]
-    pub accumulator: Box<Account<'info,RuleAccumulator>>,
-
-    #[account(mut)]
     pub linker: Signer<'info>,
     pub owner: Signer<'info>,
 
@@ -793,15 +1534,112 @@ pub struct ApproveDelegation<'info>{
     pub controller: Account<'info,Controller>,
 
     #[account(
+        mut,
+        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+        // status is checked in DelegationStatus
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct PauseDelegation<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct ResumeDelegation<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct RevokeDelegation<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(max_request_age: u64)]
+pub struct SetMaxRequestAge<'info>{
+    #[account(
+        seeds=[PROGRAM_CONTROLLER_SEED,owner.key().as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
         seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
         bump=delegation.bump,
-        constraint=0<delegation.requested_slot
     )]
     pub delegation: Box<Account<'info,Delegation>>,
 
     pub owner: Signer<'info>,
 }
 
+#[derive(Accounts)]
+#[instruction()]
+pub struct MigrateDelegation<'info>{
+    /// CHECK: a delegation in the layout of delegate::LegacyDelegation; checked in process
+    #[account(
+        mut,
+        owner=crate::ID,
+    )]
+    pub delegation: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction()]
 pub struct RejectDelegation<'info>{
@@ -818,8 +1656,7 @@ pub struct RejectDelegation<'info>{
         close = controller,
         seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
         bump=delegation.bump,
-        constraint=0<delegation.requested_slot,
-        constraint=delegation.requested_slot + 1000 < clock.slot
+        constraint=delegation.status(clock.slot)==DelegationStatus::Expired,
     )]
     pub delegation: Box<Account<'info,Delegation>>,
 
@@ -829,59 +1666,168 @@ pub struct RejectDelegation<'info>{
     pub clock: Sysvar<'info, Clock>,
 }
 
-// TODO: write CloseTokenAccount
+// TODO: write CloseTokenAccount
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct CloseDelegation<'info>{
+    #[account(
+        mut,
+        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
+        bump=controller.bump,
+        constraint=controller.owner==owner.key(),
+    )]
+    pub controller: Account<'info,Controller>,
+
+    #[account(
+        mut,
+        close = linker,
+        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    pub owner: Signer<'info>,
+    #[account(mut)]
+    pub linker: Signer<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+}
+
+
+
+
+#[derive(Accounts)]
+#[instruction(amount: u64,tree: Vec<u8>)]
+pub struct CreateSpendRequestDirect<'info>{
+
+    #[account(
+        // status is checked in process so that the error says why
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    ///CHECK: skip check
+    #[account(
+        init,
+        signer,
+        payer = linker,
+        space=spend_request_account_size(&tree,delegation.state.list.len()),
+    )]
+    pub request: Account<'info,SpendRequest>,
+
+    // SOURCE OF FUNDS
+    // we only spend from an ATA account.  This way, we can enforce balance constraints.
+    // TODO: create instruction to consolidate token account balances of the same mint, and close those token accounts
+    #[account(
+        mut,
+        constraint=delegation_vault.owner==delegation.key(),
+        constraint=delegation_vault.mint==destination_vault.mint,
+        constraint=is_ata(&delegation_vault.key(),&delegation.key(),&delegation_vault.mint),
+    )]
+    pub delegation_vault: Box<Account<'info,TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = linker,
+        associated_token::mint = mint,
+        associated_token::authority = destination_owner,
+    )]
+    pub destination_vault: Box<Account<'info,TokenAccount>>,
+
+    /// CHECK: we only need the pubkey for destination
+    pub destination_owner: SystemAccount<'info>,
+
+    pub mint: Account<'info,Mint>,
+
+    #[account(mut)]
+    pub linker: Signer<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info,AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64,tree: Vec<u8>,idempotency_key: [u8;32])]
+pub struct CreateSpendRequestPda<'info>{
+
+    #[account(
+        // status is checked in process so that the error says why
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    // no second signer, so a program can create this by CPI
+    #[account(
+        init,
+        payer = linker,
+        space=spend_request_account_size(&tree,delegation.state.list.len()),
+        seeds=[PROGRAM_SPEND_REQUEST_SEED,delegation.key().as_ref(),idempotency_key.as_ref()],
+        bump,
+    )]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // never closed; init fails if the key was used before, even after the request is gone
+    #[account(
+        init,
+        payer = linker,
+        space=8+std::mem::size_of::<SpendReceipt>(),
+        seeds=[PROGRAM_SPEND_RECEIPT_SEED,delegation.key().as_ref(),idempotency_key.as_ref()],
+        bump,
+    )]
+    pub receipt: Box<Account<'info,SpendReceipt>>,
 
-#[derive(Accounts)]
-#[instruction()]
-pub struct CloseDelegation<'info>{
+    // SOURCE OF FUNDS
     #[account(
         mut,
-        seeds=[PROGRAM_CONTROLLER_SEED,controller.owner.as_ref()],
-        bump=controller.bump,
-        constraint=controller.owner==owner.key(),
+        constraint=delegation_vault.owner==delegation.key(),
+        constraint=delegation_vault.mint==destination_vault.mint,
+        constraint=is_ata(&delegation_vault.key(),&delegation.key(),&delegation_vault.mint),
     )]
-    pub controller: Account<'info,Controller>,
+    pub delegation_vault: Box<Account<'info,TokenAccount>>,
 
     #[account(
-        mut,
-        close = linker,
-        seeds=[PROGRAM_DELEGATION_SEED,controller.key().as_ref(),delegation.rule_set_hash.as_ref()],
-        bump=delegation.bump,
+        init_if_needed,
+        payer = linker,
+        associated_token::mint = mint,
+        associated_token::authority = destination_owner,
     )]
-    pub delegation: Box<Account<'info,Delegation>>,
+    pub destination_vault: Box<Account<'info,TokenAccount>>,
+
+    /// CHECK: we only need the pubkey for destination
+    pub destination_owner: SystemAccount<'info>,
+
+    pub mint: Account<'info,Mint>,
 
-    pub owner: Signer<'info>,
     #[account(mut)]
     pub linker: Signer<'info>,
 
-    pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info,AssociatedToken>,
 }
 
-
-
-
 #[derive(Accounts)]
 #[instruction(amount: u64,tree: Vec<u8>)]
-pub struct CreateSpendRequestDirect<'info>{
+pub struct CreateSpendRequestToProgram<'info>{
 
     #[account(
-        //constraint=delegation.requested_slot==0,
+        // status is checked in process so that the error says why
     )]
     pub delegation: Box<Account<'info,Delegation>>,
 
-    ///CHECK: skip check
     #[account(
         init,
         signer,
         payer = linker,
-        space=8+std::mem::size_of::<SpendRequest>()+8+tree.len()+300,
+        space=spend_request_account_size(&tree,delegation.state.list.len()),
     )]
     pub request: Account<'info,SpendRequest>,
 
     // SOURCE OF FUNDS
-    // we only spend from an ATA account.  This way, we can enforce balance constraints.
-    // TODO: create instruction to consolidate token account balances of the same mint, and close those token accounts
     #[account(
         mut,
         constraint=delegation_vault.owner==delegation.key(),
@@ -890,27 +1836,74 @@ pub struct CreateSpendRequestDirect<'info>{
     )]
     pub delegation_vault: Box<Account<'info,TokenAccount>>,
 
+    // program vaults are often not ATAs, so any token account of destination_owner will do
     #[account(
-        init_if_needed,
-        payer = linker,
-        associated_token::mint = mint,
-        associated_token::authority = destination_owner,
+        mut,
+        token::mint = mint,
+        token::authority = destination_owner,
     )]
     pub destination_vault: Box<Account<'info,TokenAccount>>,
 
-    /// CHECK: we only need the pubkey for destination
-    pub destination_owner: SystemAccount<'info>,
+    /// CHECK: its owner is the program that ProgramConstraint checks
+    #[account(
+        constraint=destination_owner.owner!=&System::id(),
+    )]
+    pub destination_owner: AccountInfo<'info>,
 
     pub mint: Account<'info,Mint>,
 
     #[account(mut)]
     pub linker: Signer<'info>,
 
-    pub rent: Sysvar<'info, Rent>,
     pub system_program: Program<'info, System>,
     pub clock: Sysvar<'info, Clock>,
     pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info,AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64, destination_vault: Pubkey)]
+pub struct ApproveSpendRequest<'info>{
+    // every approval makes room for itself, so anyone approving cannot crowd out the authorizers
+    #[account(
+        mut,
+        realloc=request.to_account_info().data_len()+request.approval_space(&authorizer.key()),
+        realloc::payer=authorizer,
+        realloc::zero=false,
+    )]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // the authorization rules decide whether this approval counts
+    #[account(mut)]
+    pub authorizer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct CancelSpendRequest<'info>{
+    #[account(
+        mut,
+        close=linker,
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    /// CHECK: only loaded when the controller owner cancels, so the linker can cancel
+    /// after the delegation is closed
+    pub delegation: AccountInfo<'info>,
+
+    /// CHECK: only loaded when the controller owner cancels
+    pub controller: AccountInfo<'info>,
+
+    /// CHECK: the linker paid the rent, so the rent goes back to it
+    #[account(mut)]
+    pub linker: AccountInfo<'info>,
+
+    // the linker or the controller owner; see process
+    pub authority: Signer<'info>,
+
+    pub clock: Sysvar<'info, Clock>,
 }
 
 #[derive(Accounts)]
@@ -1027,6 +2020,163 @@ pub struct SpendProcessSweep<'info>{
 }
 
 
+#[derive(Accounts)]
+#[instruction(valid_from: i64, valid_until: i64)]
+pub struct SpendProcessTimeWindow<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+
+    pub clock: Sysvar<'info, Clock>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(max_total: u64)]
+pub struct SpendProcessBudgetCap<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // this is the mint of the rule, not the mint of the spend request
+    pub required_mint: Account<'info,Mint>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(max_amount: u64)]
+pub struct SpendProcessAmountConstraint<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // this is the mint of the rule, not the mint of the spend request
+    pub required_mint: Account<'info,Mint>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(root: [u8;32], proof: Vec<[u8;32]>)]
+pub struct SpendProcessDestinationAllowlist<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // the proof may be for this account or for its owner
+    #[account(
+        constraint=destination_vault.key()==request.context.destination_vault,
+    )]
+    pub destination_vault: Account<'info,TokenAccount>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction()]
+pub struct SpendProcessAddressBook<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    // the rule hash pins the address of the book, so entries are read from whatever it holds now
+    pub book: Box<Account<'info,AddressBook>>,
+
+    // the entry may be for this account or for its owner
+    #[account(
+        constraint=destination_vault.key()==request.context.destination_vault,
+    )]
+    pub destination_vault: Account<'info,TokenAccount>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(threshold: u8, authorizer_list: Vec<Pubkey>)]
+pub struct SpendProcessMultisig<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(required_signer: Pubkey)]
+pub struct SpendProcessEd25519Signature<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    /// CHECK: the address is checked; we read the other instructions in this transaction from it
+    #[account(
+        address=anchor_lang::solana_program::sysvar::instructions::ID,
+    )]
+    pub instructions: AccountInfo<'info>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(eth_address: [u8;20])]
+pub struct SpendProcessSecp256k1Signature<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    /// CHECK: the address is checked; we read the other instructions in this transaction from it
+    #[account(
+        address=anchor_lang::solana_program::sysvar::instructions::ID,
+    )]
+    pub instructions: AccountInfo<'info>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
+#[derive(Accounts)]
+#[instruction(required_program: Pubkey, position: u8)]
+pub struct SpendProcessCallerProgram<'info>{
+    #[account(mut)]
+    pub request: Box<Account<'info,SpendRequest>>,
+
+    /// CHECK: the address is checked; we read the other instructions in this transaction from it
+    #[account(
+        address=anchor_lang::solana_program::sysvar::instructions::ID,
+    )]
+    pub instructions: AccountInfo<'info>,
+
+    #[account(
+        constraint=request.context.linker==linker.key(),
+    )]
+    pub linker: Signer<'info>,
+}
+
+
 #[derive(Accounts)]
 #[instruction()]
 pub struct CompleteSpendRequestDirect<'info>{
@@ -1076,7 +2226,55 @@ pub struct CompleteSpendRequestDirect<'info>{
     )]
     pub linker: Signer<'info>,
 
+    /// CHECK: the address is checked; caller program leaves are checked again against it
+    #[account(
+        address=anchor_lang::solana_program::sysvar::instructions::ID,
+    )]
+    pub instructions: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(amount: u64,tree: Vec<u8>,param_list: Vec<RuleParam>)]
+pub struct SpendDirect<'info>{
+
+    #[account(
+        mut,
+        seeds=[PROGRAM_DELEGATION_SEED,delegation.controller.as_ref(),delegation.rule_set_hash.as_ref()],
+        bump=delegation.bump,
+        // status is checked in process so that the error says why
+    )]
+    pub delegation: Box<Account<'info,Delegation>>,
+
+    // SOURCE OF FUNDS
+    #[account(
+        mut,
+        constraint=delegation_vault.owner==delegation.key(),
+        constraint=delegation_vault.mint==destination_vault.mint,
+        constraint=is_ata(&delegation_vault.key(),&delegation.key(),&delegation_vault.mint),
+    )]
+    pub delegation_vault: Box<Account<'info,TokenAccount>>,
+
+    // DESTINATION OF FUNDS
+    #[account(
+        init_if_needed,
+        payer = linker,
+        associated_token::mint = mint,
+        associated_token::authority = destination_owner,
+    )]
+    pub destination_vault: Box<Account<'info,TokenAccount>>,
+
+    /// CHECK: we only need the pubkey for destination
+    pub destination_owner: SystemAccount<'info>,
+
+    pub mint: Account<'info,Mint>,
+
+    #[account(mut)]
+    pub linker: Signer<'info>,
 
+    pub system_program: Program<'info, System>,
+    pub clock: Sysvar<'info, Clock>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info,AssociatedToken>,
 }
 
 fn is_ata<'a>(vault: &Pubkey,owner: &'a Pubkey, mint: &'a Pubkey)->bool{
@@ -1100,7 +2298,6 @@ pub struct ConsolidateVault<'info>{
     #[account(
         seeds=[PROGRAM_DELEGATION_SEED,delegation.controller.as_ref(),delegation.rule_set_hash.as_ref()],
         bump=delegation.bump,
-        constraint=delegation.requested_slot==0,
     )]
     pub delegation: Box<Account<'info,Delegation>>,
 
@@ -1168,6 +2365,10 @@ pub struct ConsolidateVault<'info>{
 
 pub const PROGRAM_CONTROLLER_SEED: &[u8] = b"controller";
 pub const PROGRAM_DELEGATION_SEED: &[u8] = b"delegation";
+pub const PROGRAM_ADDRESS_BOOK_SEED: &[u8] = b"address_book";
+pub const PROGRAM_SPEND_REQUEST_SEED: &[u8] = b"spend_request";
+pub const PROGRAM_SPEND_RECEIPT_SEED: &[u8] = b"spend_receipt";
+pub const PROGRAM_ACCUMULATOR_SEED: &[u8] = b"accumulator";
 
 fn log_me(_s: &str)->bool{
     //msg!("{}",s);
@@ -1,18 +1,34 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::entrypoint::ProgramResult;
-use anchor_lang::solana_program::hash::{hash, Hash, HASH_BYTES};
+use anchor_lang::solana_program::hash::{hash, hashv, Hash, HASH_BYTES};
 
 use crate::errors::TreasuryError;
 use crate::spend::{SpendState, TransferContext};
-use crate::{nplog, tree, CreateRuleAccumulator};
+use crate::{nplog, tree, AbortAccumulator, CreateRuleAccumulator, ID, PROGRAM_ACCUMULATOR_SEED};
 use anchor_lang;
-use anchor_lang::solana_program::instruction::Instruction;
 
 pub(crate) const RULE_RATE_LIMITER: u8 = 1;
 pub(crate) const RULE_PROGRAM_CONSTRAINT: u8 = 2;
 pub(crate) const RULE_AUTHORIZATION_CONSTRAINT: u8 = 3;
 pub(crate) const RULE_BALANCE_CONSTRAINT: u8 = 4;
 pub(crate) const RULE_SWEEP: u8 = 5;
+pub(crate) const RULE_TIME_WINDOW: u8 = 6;
+pub(crate) const RULE_BUDGET_CAP: u8 = 7;
+pub(crate) const RULE_AMOUNT_CONSTRAINT: u8 = 8;
+pub(crate) const RULE_DESTINATION_ALLOWLIST: u8 = 9;
+pub(crate) const RULE_ADDRESS_BOOK: u8 = 10;
+pub(crate) const RULE_MULTISIG: u8 = 11;
+pub(crate) const RULE_ED25519_SIGNATURE: u8 = 12;
+pub(crate) const RULE_SECP256K1_SIGNATURE: u8 = 13;
+pub(crate) const RULE_CALLER_PROGRAM: u8 = 14;
+
+// rule hash formats; the version is recorded on the Delegation so that old delegations stay verifiable
+/// index || prev_hash || serialized_rule
+pub const RULE_HASH_V0: u8 = 0;
+/// HASH_RULE_LEAF || version || rule id || index || prev_hash || serialized_rule
+pub const RULE_HASH_V1: u8 = 1;
+/// the format used for new rule accumulators
+pub const RULE_HASH_LATEST: u8 = RULE_HASH_V1;
 
 #[account]
 pub struct RuleAccumulator {
@@ -20,19 +36,28 @@ pub struct RuleAccumulator {
     pub index: u8,
     pub count: u8,
     pub hash: [u8; 32],
+    pub version: u8,
+    // rent goes back here on Delegate or AbortAccumulator
+    pub linker: Pubkey,
+    pub nonce: u64,
+    pub bump: u8,
 }
 
 impl RuleAccumulator {
     // use this for unit tests
     pub fn new(controller: &Pubkey, tree: &[u8]) -> Result<Self> {
         nplog!("ra - 1");
-        let (_x, count) = tree::deserialize(&tree.to_vec())?;
+        let count = tree::validate(tree)?;
         nplog!("ra - 2");
         let mut ra = Self {
             controller: controller.clone(),
             index: 0,
             count,
             hash: RuleAccumulator::hash_init(),
+            version: RULE_HASH_LATEST,
+            linker: Pubkey::default(),
+            nonce: 0,
+            bump: 0,
         };
         nplog!("ra - 3");
         ra.hash_tree(&tree.to_vec());
@@ -45,12 +70,13 @@ impl RuleAccumulator {
 
     // use this in anchor entrypoints
     pub fn init(&mut self, controller: &Pubkey, tree: &[u8]) -> Result<()> {
-        let (_x, count) = tree::deserialize(&tree.to_vec())?;
+        let count = tree::validate(tree)?;
 
         self.controller = controller.clone();
         self.index = 0;
         self.count = count;
         self.hash = RuleAccumulator::hash_init();
+        self.version = RULE_HASH_LATEST;
         self.hash_tree(&tree.to_vec());
 
         Ok(())
@@ -65,7 +91,7 @@ impl RuleAccumulator {
     }
 
     pub fn add(&mut self, rule: &dyn Rule) -> Result<()> {
-        self.hash = rule.hash(self.index, &self.hash)?;
+        self.hash = rule.hash(self.version, self.index, &self.hash)?;
         ////msg!("add i={} hash={:X?}",self.index,&self.hash);
         self.index += 1;
         if self.count < self.index {
@@ -78,12 +104,37 @@ impl RuleAccumulator {
 
 impl<'info> CreateRuleAccumulator<'info> {
     // rule accumulator holds SOL to pay rent, including delegation account
-    pub fn process(&mut self, tree: Vec<u8>) -> ProgramResult {
+    pub fn process(&mut self, bump: u8, tree: Vec<u8>, nonce: u64) -> ProgramResult {
         self.accumulator.init(&self.controller.key(), &tree)?;
+        self.accumulator.linker = self.linker.key();
+        self.accumulator.nonce = nonce;
+        self.accumulator.bump = bump;
         Ok(())
     }
 }
 
+impl<'info> AbortAccumulator<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let authority = self.authority.key();
+        if authority != self.accumulator.linker && authority != self.controller.owner {
+            return Err(ProgramError::Custom(
+                TreasuryError::RuleAccumulatorAbortNotAllowed.into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn accumulator_id(controller: &Pubkey, nonce: u64) -> Pubkey {
+    let x = [
+        PROGRAM_ACCUMULATOR_SEED,
+        controller.as_ref(),
+        &nonce.to_le_bytes(),
+    ];
+    let (ans, _bump) = Pubkey::find_program_address(&x, &ID);
+    ans
+}
+
 pub fn hash_rule_set(set: &[u8]) -> Hash {
     let mut first_hash: [u8; HASH_BYTES * 2] = [0u8; HASH_BYTES * 2];
     if set.len() == 0 {
@@ -108,11 +159,31 @@ pub fn hash_rule_set(set: &[u8]) -> Hash {
 
 pub trait Rule<'b> {
     fn id(&self) -> u8;
-    fn hash<'a>(&'a self, index: u8, prev_hash: &'a [u8]) -> Result<[u8; 32]>;
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; 32]>;
     fn process<'a>(&'a self, state: &mut SpendState, context: &TransferContext) -> Result<()>;
 }
 
-pub(crate) fn generic_hash<'a>(index: &u8, serialized_rule: &[u8], prev_hash: &[u8]) -> [u8; 32] {
+pub(crate) fn generic_hash(
+    version: u8,
+    id: u8,
+    index: &u8,
+    serialized_rule: &[u8],
+    prev_hash: &[u8],
+) -> Result<[u8; 32]> {
+    match version {
+        RULE_HASH_V0 => Ok(generic_hash_v0(index, serialized_rule, prev_hash)),
+        RULE_HASH_V1 => {
+            // commit to the rule id so that rules with identical serializations
+            // (ie ProgramConstraint and AuthorizationConstraint) hash differently
+            let header = [version, id, *index];
+            Ok(hashv(&[HASH_RULE_LEAF, &header, prev_hash, serialized_rule]).to_bytes())
+        }
+        _ => Err(TreasuryError::RuleHashVersionUnknown.into()),
+    }
+}
+
+// the original format; only kept to verify delegations created before RULE_HASH_V1
+fn generic_hash_v0(index: &u8, serialized_rule: &[u8], prev_hash: &[u8]) -> [u8; 32] {
     ////msg!("hashing rule={:X?}",serialized_rule);
     let mut all = Vec::new();
     ////msg!("hashing - 1");
@@ -135,3 +206,4 @@ pub const ZERO_HASH: [u8; 32] = [
 ];
 
 pub const HASH_RULE_SET: &[u8] = b"hashing_rule_set";
+pub const HASH_RULE_LEAF: &[u8] = b"hashing_rule_leaf";
@@ -0,0 +1,96 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::addressbook::AddressBookStatus;
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_ADDRESS_BOOK};
+use crate::spend::{SpendState, TransferContext};
+use crate::{RuleAddAddressBook, SpendProcessAddressBook};
+
+impl<'info> RuleAddAddressBook<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let rule = AddressBookConstraint::new(&self.book.key(), &Pubkey::default(), None, None);
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessAddressBook<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let rule = AddressBookConstraint::new(
+            &self.book.key(),
+            &self.destination_vault.key(),
+            self.book.lookup(&self.destination_vault.key()),
+            self.book.lookup(&self.destination_vault.owner),
+        );
+        self.request.process(&rule)?;
+        Ok(())
+    }
+}
+
+/// Passes if the destination token account or its owner is marked allowed in the address book,
+/// and neither is marked blocked.
+/// Only the address of the book is committed to in the rule hash; entries are read at process time.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct AddressBookConstraint {
+    pub book: Pubkey,
+    pub destination_vault: Pubkey,
+    pub vault_status: Option<AddressBookStatus>,
+    pub owner_status: Option<AddressBookStatus>,
+}
+
+// we only want to serialize the book to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct AddressBookConstraintOnly {
+    pub book: Pubkey,
+}
+
+impl AddressBookConstraint {
+    pub fn new(
+        book: &Pubkey,
+        destination_vault: &Pubkey,
+        vault_status: Option<AddressBookStatus>,
+        owner_status: Option<AddressBookStatus>,
+    ) -> Self {
+        Self {
+            book: *book,
+            destination_vault: *destination_vault,
+            vault_status,
+            owner_status,
+        }
+    }
+
+    pub fn for_serialization(&self) -> AddressBookConstraintOnly {
+        AddressBookConstraintOnly { book: self.book }
+    }
+}
+
+impl<'b> Rule<'b> for AddressBookConstraint {
+    fn id(&self) -> u8 {
+        RULE_ADDRESS_BOOK
+    }
+
+    fn process(&self, _state: &mut SpendState, context: &TransferContext) -> Result<()> {
+        if context.destination_vault != self.destination_vault {
+            return Err(TreasuryError::RuleAddressBookWrongDestination.into());
+        }
+        let list = [self.vault_status, self.owner_status];
+        if list.contains(&Some(AddressBookStatus::Blocked)) {
+            return Err(TreasuryError::RuleAddressBookBlocked.into());
+        }
+        if list.contains(&Some(AddressBookStatus::Allowed)) {
+            return Ok(());
+        }
+        Err(TreasuryError::RuleAddressBookNotListed.into())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<AddressBookConstraintOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
@@ -0,0 +1,167 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::{hashv, HASH_BYTES};
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_DESTINATION_ALLOWLIST, ZERO_HASH};
+use crate::spend::{SpendState, TransferContext};
+use crate::{nplog, RuleAddDestinationAllowlist, SpendProcessDestinationAllowlist};
+
+/// 2^32 leaves is far more than a transaction can pay for
+pub const ALLOWLIST_MAX_PROOF: usize = 32;
+
+pub const HASH_ALLOWLIST_LEAF: &[u8] = b"hashing_allowlist_leaf";
+pub const HASH_ALLOWLIST_NODE: &[u8] = b"hashing_allowlist_node";
+
+impl<'info> RuleAddDestinationAllowlist<'info> {
+    pub fn process(&mut self, root: [u8; HASH_BYTES]) -> ProgramResult {
+        let rule =
+            DestinationAllowlist::new(root, &Pubkey::default(), &Pubkey::default(), Vec::new())?;
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessDestinationAllowlist<'info> {
+    pub fn process(
+        &mut self,
+        root: [u8; HASH_BYTES],
+        proof: Vec<[u8; HASH_BYTES]>,
+    ) -> ProgramResult {
+        let rule = DestinationAllowlist::new(
+            root,
+            &self.destination_vault.key(),
+            &self.destination_vault.owner,
+            proof,
+        )?;
+        self.request.process(&rule)?;
+        Ok(())
+    }
+}
+
+/// Passes if the destination token account, or the owner of that token account,
+/// is a leaf of the Merkle tree with the given root.
+/// Only the root is committed to in the rule hash; the proof comes with each spend.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct DestinationAllowlist {
+    pub root: [u8; HASH_BYTES],
+    pub destination_vault: Pubkey,
+    pub destination_owner: Pubkey,
+    pub proof: Vec<[u8; HASH_BYTES]>,
+}
+
+// we only want to serialize the root to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct DestinationAllowlistOnly {
+    pub root: [u8; HASH_BYTES],
+}
+
+impl DestinationAllowlist {
+    pub fn new(
+        root: [u8; HASH_BYTES],
+        destination_vault: &Pubkey,
+        destination_owner: &Pubkey,
+        proof: Vec<[u8; HASH_BYTES]>,
+    ) -> Result<Self> {
+        if ALLOWLIST_MAX_PROOF < proof.len() {
+            return Err(TreasuryError::RuleAllowlistProofTooLong.into());
+        }
+        Ok(Self {
+            root,
+            destination_vault: *destination_vault,
+            destination_owner: *destination_owner,
+            proof,
+        })
+    }
+
+    pub fn for_serialization(&self) -> DestinationAllowlistOnly {
+        DestinationAllowlistOnly { root: self.root }
+    }
+
+    fn is_member(&self, key: &Pubkey) -> bool {
+        let mut x = allowlist_leaf(key);
+        for sibling in &self.proof {
+            x = allowlist_parent(&x, sibling);
+        }
+        x == self.root
+    }
+}
+
+impl<'b> Rule<'b> for DestinationAllowlist {
+    fn id(&self) -> u8 {
+        RULE_DESTINATION_ALLOWLIST
+    }
+
+    fn process(&self, _state: &mut SpendState, context: &TransferContext) -> Result<()> {
+        if context.destination_vault != self.destination_vault {
+            return Err(TreasuryError::RuleAllowlistWrongDestination.into());
+        }
+        nplog!("allowlist proof length {}", self.proof.len());
+        if self.is_member(&self.destination_vault) || self.is_member(&self.destination_owner) {
+            return Ok(());
+        }
+        Err(TreasuryError::RuleAllowlistDestinationNotAllowed.into())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<DestinationAllowlistOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
+
+/// The leaf for a destination token account or destination owner.
+pub fn allowlist_leaf(key: &Pubkey) -> [u8; HASH_BYTES] {
+    hashv(&[HASH_ALLOWLIST_LEAF, key.as_ref()]).to_bytes()
+}
+
+// pairs are sorted so that a proof does not need to say left or right
+fn allowlist_parent(a: &[u8; HASH_BYTES], b: &[u8; HASH_BYTES]) -> [u8; HASH_BYTES] {
+    if a <= b {
+        hashv(&[HASH_ALLOWLIST_NODE, a, b]).to_bytes()
+    } else {
+        hashv(&[HASH_ALLOWLIST_NODE, b, a]).to_bytes()
+    }
+}
+
+// the last node of an odd layer moves up unchanged
+fn allowlist_next_layer(layer: &[[u8; HASH_BYTES]]) -> Vec<[u8; HASH_BYTES]> {
+    layer
+        .chunks(2)
+        .map(|pair| match pair {
+            [a, b] => allowlist_parent(a, b),
+            [a] => *a,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Build the root off chain from the list of allowed keys.
+pub fn allowlist_root(keys: &[Pubkey]) -> [u8; HASH_BYTES] {
+    if keys.is_empty() {
+        return ZERO_HASH;
+    }
+    let mut layer: Vec<[u8; HASH_BYTES]> = keys.iter().map(allowlist_leaf).collect();
+    while 1 < layer.len() {
+        layer = allowlist_next_layer(&layer);
+    }
+    layer[0]
+}
+
+/// Build the proof off chain for keys\[index\].
+pub fn allowlist_proof(keys: &[Pubkey], index: usize) -> Vec<[u8; HASH_BYTES]> {
+    let mut proof = Vec::new();
+    let mut layer: Vec<[u8; HASH_BYTES]> = keys.iter().map(allowlist_leaf).collect();
+    let mut i = index;
+    while 1 < layer.len() {
+        if let Some(sibling) = layer.get(i ^ 1) {
+            proof.push(*sibling);
+        }
+        layer = allowlist_next_layer(&layer);
+        i /= 2;
+    }
+    proof
+}
@@ -54,6 +54,11 @@ impl<'info> SpendProcessAuthorizationConstraintNoSigner<'info> {
                 break;
             }
         }
+        // or approved earlier with approve_spend_request
+        if self.request.is_approved(&self.required_authorizer.key()) {
+            authorizer = Some(self.required_authorizer.key());
+            nplog!("rule process acns - 3");
+        }
         let rule = AuthorizationConstraint::new(&self.required_authorizer.key(), authorizer);
         self.request.process(&rule)?;
 
@@ -138,7 +143,7 @@ impl<'b> Rule<'b> for AuthorizationConstraint {
         Ok(())
     }
 
-    fn hash<'a>(&'a self, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
         let mut x = [0u8; std::mem::size_of::<AuthorizationConstraintOnly>()];
         let mut cursor = std::io::Cursor::new(x.as_mut());
         let ac = AuthorizationConstraintOnly {
@@ -146,6 +151,6 @@ impl<'b> Rule<'b> for AuthorizationConstraint {
         };
         ac.serialize(&mut cursor)?;
         //msg!("_______+++++rule({})={:X?}",x.len(),&x);
-        return Ok(generic_hash(&index, &x, prev_hash));
+        generic_hash(version, self.id(), &index, &x, prev_hash)
     }
 }
@@ -0,0 +1,78 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_BUDGET_CAP};
+use crate::spend::{Recheck, SpendState, TransferContext};
+use crate::{nplog, RuleAddBudgetCap, SpendProcessBudgetCap};
+
+impl<'info> RuleAddBudgetCap<'info> {
+    pub fn process(&mut self, max_total: u64) -> ProgramResult {
+        let rule = BudgetCap::new(&self.mint.key(), max_total)?;
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessBudgetCap<'info> {
+    pub fn process(&mut self, max_total: u64) -> ProgramResult {
+        let rule = BudgetCap::new(&self.required_mint.key(), max_total)?;
+        // another request can spend from the delegation before this one is completed
+        self.request
+            .process_with_recheck(&rule, Recheck::BudgetCap(rule.clone()))?;
+        Ok(())
+    }
+}
+
+/// Caps the total amount of one mint that the delegation can ever spend.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct BudgetCap {
+    pub mint: Pubkey,
+    pub max_total: u64,
+}
+
+impl BudgetCap {
+    pub fn new(mint: &Pubkey, max_total: u64) -> Result<Self> {
+        if max_total == 0 {
+            return Err(TreasuryError::RuleBudgetCapCannotBeZero.into());
+        }
+        Ok(Self {
+            mint: *mint,
+            max_total,
+        })
+    }
+}
+
+impl<'b> Rule<'b> for BudgetCap {
+    fn id(&self) -> u8 {
+        RULE_BUDGET_CAP
+    }
+
+    fn process(&self, state: &mut SpendState, context: &TransferContext) -> Result<()> {
+        if context.mint != self.mint {
+            return Err(TreasuryError::RuleBudgetCapWrongMint.into());
+        }
+        let space = state.find(&context.mint)?;
+        let total = space.total_spent.saturating_add(context.amount);
+        nplog!(
+            "budget total spent {} next spend {} max {}",
+            space.total_spent,
+            context.amount,
+            self.max_total
+        );
+        if self.max_total < total {
+            return Err(TreasuryError::RuleBudgetCapExceeded.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<BudgetCap>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
@@ -0,0 +1,124 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+use anchor_lang::solana_program::instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_CALLER_PROGRAM};
+use crate::spend::{Recheck, SpendState, TransferContext};
+use crate::{nplog, RuleAddCallerProgram, SpendProcessCallerProgram};
+
+/// The position that means "safejar must be called by CPI from required_program".
+pub const CALLER_BY_CPI: u8 = u8::MAX;
+
+impl<'info> RuleAddCallerProgram<'info> {
+    pub fn process(&mut self, required_program: Pubkey, position: u8) -> ProgramResult {
+        let rule = CallerProgram::new(&required_program, position, false);
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessCallerProgram<'info> {
+    pub fn process(&mut self, required_program: Pubkey, position: u8) -> ProgramResult {
+        let has_called = is_called_by(&self.instructions, &required_program, position);
+        nplog!(
+            "caller {} at position {} found {}",
+            required_program,
+            position,
+            has_called
+        );
+        let rule = CallerProgram::new(&required_program, position, has_called);
+        // otherwise the linker could complete the request without the program
+        self.request.process_with_recheck(
+            &rule,
+            Recheck::Caller {
+                required_program,
+                position,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Passes when required_program drives the spend, whoever the linker is.
+/// With position CALLER_BY_CPI, the instruction processing this rule must be a CPI made
+/// under a top level instruction to required_program. That is the outermost program:
+/// with A -> B -> safejar the rule checks A, and any program in between passes.
+/// Otherwise the instruction at that index of the transaction must be to required_program.
+/// A spend request checks this again in the transaction that completes it.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct CallerProgram {
+    pub required_program: Pubkey,
+    pub position: u8,
+    pub has_called: bool,
+}
+
+// has_called is not part of the rule set
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct CallerProgramOnly {
+    pub required_program: Pubkey,
+    pub position: u8,
+}
+
+impl CallerProgram {
+    pub fn new(required_program: &Pubkey, position: u8, has_called: bool) -> Self {
+        Self {
+            required_program: *required_program,
+            position,
+            has_called,
+        }
+    }
+
+    pub fn for_serialization(&self) -> CallerProgramOnly {
+        CallerProgramOnly {
+            required_program: self.required_program,
+            position: self.position,
+        }
+    }
+}
+
+impl<'b> Rule<'b> for CallerProgram {
+    fn id(&self) -> u8 {
+        RULE_CALLER_PROGRAM
+    }
+
+    fn process(&self, _state: &mut SpendState, _context: &TransferContext) -> Result<()> {
+        if !self.has_called {
+            return Err(TreasuryError::RuleCallerProgramMismatch.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<CallerProgramOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
+
+// the instructions sysvar only lists top level instructions, so for a CPI we can only
+// say which program the transaction called, not which program called us directly
+pub(crate) fn is_called_by(instructions: &AccountInfo, program_id: &Pubkey, position: u8) -> bool {
+    let index = if position == CALLER_BY_CPI {
+        if get_stack_height() <= TRANSACTION_LEVEL_STACK_HEIGHT {
+            return false;
+        }
+        match load_current_index_checked(instructions) {
+            Ok(i) => i as usize,
+            Err(_) => return false,
+        }
+    } else {
+        position as usize
+    };
+    match load_instruction_at_checked(index, instructions) {
+        Ok(ix) => ix.program_id == *program_id,
+        Err(_) => false,
+    }
+}
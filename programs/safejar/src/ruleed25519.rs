@@ -0,0 +1,87 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_ED25519_SIGNATURE};
+use crate::sigverify::has_ed25519_signature;
+use crate::spend::{Recheck, SpendState, TransferContext};
+use crate::{nplog, RuleAddEd25519Signature, SpendProcessEd25519Signature};
+
+impl<'info> RuleAddEd25519Signature<'info> {
+    pub fn process(&mut self, required_signer: Pubkey) -> ProgramResult {
+        let rule = Ed25519Signature::new(&required_signer, false);
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessEd25519Signature<'info> {
+    pub fn process(&mut self, required_signer: Pubkey) -> ProgramResult {
+        let message = self.request.intent_message();
+        let has_signed = has_ed25519_signature(&self.instructions, &required_signer, &message);
+        nplog!(
+            "ed25519 signature from {} found {}",
+            required_signer,
+            has_signed
+        );
+        let rule = Ed25519Signature::new(&required_signer, has_signed);
+        // two requests made before either completes get the same nonce
+        let nonce = self.request.intent_nonce();
+        self.request
+            .process_with_recheck(&rule, Recheck::IntentNonce { nonce })?;
+        Ok(())
+    }
+}
+
+/// Passes when an Ed25519 program instruction in the same transaction carries a signature
+/// by required_signer over the spend intent (see SpendRequest::intent_message).
+/// The key never has to sign a transaction, so any relayer can act as linker.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct Ed25519Signature {
+    pub required_signer: Pubkey,
+    pub has_signed: bool,
+}
+
+// we only want to serialize required_signer to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct Ed25519SignatureOnly {
+    pub required_signer: Pubkey,
+}
+
+impl Ed25519Signature {
+    pub fn new(required_signer: &Pubkey, has_signed: bool) -> Self {
+        Self {
+            required_signer: *required_signer,
+            has_signed,
+        }
+    }
+
+    pub fn for_serialization(&self) -> Ed25519SignatureOnly {
+        Ed25519SignatureOnly {
+            required_signer: self.required_signer,
+        }
+    }
+}
+
+impl<'b> Rule<'b> for Ed25519Signature {
+    fn id(&self) -> u8 {
+        RULE_ED25519_SIGNATURE
+    }
+
+    fn process(&self, _state: &mut SpendState, _context: &TransferContext) -> Result<()> {
+        if !self.has_signed {
+            return Err(TreasuryError::RuleEd25519SignatureMissing.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<Ed25519SignatureOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
@@ -0,0 +1,68 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_AMOUNT_CONSTRAINT};
+use crate::spend::{SpendState, TransferContext};
+use crate::{RuleAddAmountConstraint, SpendProcessAmountConstraint};
+
+impl<'info> RuleAddAmountConstraint<'info> {
+    pub fn process(&mut self, max_amount: u64) -> ProgramResult {
+        let rule = AmountConstraint::new(&self.mint.key(), max_amount)?;
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessAmountConstraint<'info> {
+    pub fn process(&mut self, max_amount: u64) -> ProgramResult {
+        let rule = AmountConstraint::new(&self.required_mint.key(), max_amount)?;
+        self.request.process(&rule)?;
+        Ok(())
+    }
+}
+
+/// Caps a single transfer; no spending history is involved.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct AmountConstraint {
+    pub mint: Pubkey,
+    pub max_amount: u64,
+}
+
+impl AmountConstraint {
+    pub fn new(mint: &Pubkey, max_amount: u64) -> Result<Self> {
+        if max_amount == 0 {
+            return Err(TreasuryError::RuleAmountConstraintCannotBeZero.into());
+        }
+        Ok(Self {
+            mint: *mint,
+            max_amount,
+        })
+    }
+}
+
+impl<'b> Rule<'b> for AmountConstraint {
+    fn id(&self) -> u8 {
+        RULE_AMOUNT_CONSTRAINT
+    }
+
+    fn process(&self, _state: &mut SpendState, context: &TransferContext) -> Result<()> {
+        if context.mint != self.mint {
+            return Err(TreasuryError::RuleAmountConstraintWrongMint.into());
+        }
+        if self.max_amount < context.amount {
+            return Err(TreasuryError::RuleAmountConstraintExceeded.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<AmountConstraint>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
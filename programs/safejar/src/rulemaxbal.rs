@@ -32,7 +32,7 @@ impl<'info> SpendProcessBalanceConstraint<'info>{
             self.delegation_vault.amount,
             max_bal,
         );
-        self.request.process(&rule)?;
+        self.request.process_with_balance(&rule,&self.delegation_vault.key(),self.delegation_vault.amount)?;
         
         Ok(())
     }
@@ -90,12 +90,12 @@ impl<'b> Rule<'b> for BalanceConstraint{
         Ok(())
     }
 
-    fn hash<'a>(&'a self,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
+    fn hash<'a>(&'a self,version: u8,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
         let mut x=[0u8;std::mem::size_of::<BalanceConstraintOnly>()];
         let mut cursor = std::io::Cursor::new(x.as_mut());
         self.for_serialization().serialize(&mut cursor)?;
         //msg!("_______+++++rule({})={:X?}",x.len(),&x);
-        return Ok(generic_hash(&index,&x,prev_hash))
+        generic_hash(version,self.id(),&index,&x,prev_hash)
     }
 
   
@@ -0,0 +1,108 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_MULTISIG};
+use crate::spend::{SpendState, TransferContext};
+use crate::{nplog, RuleAddMultisig, SpendProcessMultisig};
+
+/// keeps the process instruction inside the transaction account limit
+pub const MULTISIG_MAX_AUTHORIZERS: usize = 10;
+
+impl<'info> RuleAddMultisig<'info> {
+    pub fn process(&mut self, threshold: u8, authorizer_list: Vec<Pubkey>) -> ProgramResult {
+        let rule = MultisigConstraint::new(threshold, authorizer_list, 0)?;
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessMultisig<'info> {
+    pub fn process(
+        &mut self,
+        threshold: u8,
+        authorizer_list: Vec<Pubkey>,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> ProgramResult {
+        // each authorizer counts once no matter how many times it is passed in,
+        // whether it signs now or approved earlier with approve_spend_request
+        let signed = authorizer_list
+            .iter()
+            .filter(|authorizer| {
+                self.request.is_approved(authorizer)
+                    || remaining_accounts
+                        .iter()
+                        .any(|a| a.is_signer && a.key() == **authorizer)
+            })
+            .count();
+        nplog!("multisig {} of {} signed", signed, authorizer_list.len());
+        let rule = MultisigConstraint::new(threshold, authorizer_list, signed as u8)?;
+        self.request.process(&rule)?;
+        Ok(())
+    }
+}
+
+/// Passes when at least threshold of the listed authorizers have signed.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct MultisigConstraint {
+    pub threshold: u8,
+    pub authorizer_list: Vec<Pubkey>,
+    pub signed: u8,
+}
+
+// we only want to serialize the threshold and the authorizers to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct MultisigConstraintOnly {
+    pub threshold: u8,
+    pub authorizer_list: Vec<Pubkey>,
+}
+
+impl MultisigConstraint {
+    pub fn new(threshold: u8, authorizer_list: Vec<Pubkey>, signed: u8) -> Result<Self> {
+        if MULTISIG_MAX_AUTHORIZERS < authorizer_list.len() {
+            return Err(TreasuryError::RuleMultisigTooManyAuthorizers.into());
+        }
+        if threshold == 0 || authorizer_list.len() < threshold as usize {
+            return Err(TreasuryError::RuleMultisigBadThreshold.into());
+        }
+        for (i, a) in authorizer_list.iter().enumerate() {
+            if authorizer_list[i + 1..].contains(a) {
+                return Err(TreasuryError::RuleMultisigDuplicateAuthorizer.into());
+            }
+        }
+        Ok(Self {
+            threshold,
+            authorizer_list,
+            signed,
+        })
+    }
+
+    pub fn for_serialization(&self) -> MultisigConstraintOnly {
+        MultisigConstraintOnly {
+            threshold: self.threshold,
+            authorizer_list: self.authorizer_list.clone(),
+        }
+    }
+}
+
+impl<'b> Rule<'b> for MultisigConstraint {
+    fn id(&self) -> u8 {
+        RULE_MULTISIG
+    }
+
+    fn process(&self, _state: &mut SpendState, _context: &TransferContext) -> Result<()> {
+        if self.signed < self.threshold {
+            return Err(TreasuryError::RuleMultisigNotEnoughSigners.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        // the authorizer list has a variable length, so there is no fixed size buffer
+        let x = self.for_serialization().try_to_vec()?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
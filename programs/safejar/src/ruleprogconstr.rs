@@ -36,6 +36,8 @@ impl<'info> SpendProcessProgramConstraint<'info>{
 
 #[derive(AnchorDeserialize, AnchorSerialize,Clone)]
 pub struct ProgramConstraint{
+    // owner of the destination owner; only create_spend_request_to_program lets this be
+    // anything but the System Program
     pub program_id: Pubkey,
 
 }
@@ -67,12 +69,12 @@ impl<'b> Rule<'b> for ProgramConstraint{
         Ok(())
     }
 
-    fn hash<'a>(&'a self,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
+    fn hash<'a>(&'a self,version: u8,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
         let mut x=[0u8;std::mem::size_of::<ProgramConstraint>()];
         let mut cursor = std::io::Cursor::new(x.as_mut());
         self.serialize(&mut cursor)?;
         //msg!("_______+++++rule({})={:X?}",x.len(),&x);
-        return Ok(generic_hash(&index,&x,prev_hash))
+        generic_hash(version,self.id(),&index,&x,prev_hash)
     }
 
   
@@ -4,7 +4,7 @@ use anchor_lang::solana_program::hash::HASH_BYTES;
 
 use crate::errors::TreasuryError;
 use crate::rule::{generic_hash, Rule, RULE_RATE_LIMITER};
-use crate::spend::{SpendState, TransferContext};
+use crate::spend::{Recheck, SpendState, TransferContext};
 use crate::{nplog, RuleAddRateLimiter, SpendProcessRateLimiter};
 
 impl<'info> RuleAddRateLimiter<'info> {
@@ -22,7 +22,9 @@ impl<'info> SpendProcessRateLimiter<'info> {
         //msg!("sprl - 1");
         let rule = RateLimiter::new(&self.required_mint.key(), max_spend, delta_slot)?;
         //msg!("sprl - 2");
-        self.request.process(&rule)?;
+        // another request can spend from the delegation before this one is completed
+        self.request
+            .process_with_recheck(&rule, Recheck::RateLimiter(rule.clone()))?;
         //msg!("sprl - 3");
         Ok(())
     }
@@ -67,22 +69,19 @@ impl<'b> Rule<'b> for RateLimiter {
             return Err(TreasuryError::RuleRateLimiterSlotOutOfOrder.into());
         }
 
+        // every spend still inside the window counts, not just the last one
+        let spent = space
+            .spent_in_window(tx_ctx.slot, self.delta_slot)
+            .saturating_add(tx_ctx.amount);
         nplog!(
-            "last slot {} vs context slot {}; delta {}; last spend: {}; next spend: {}",
-            space.last_slot,
+            "context slot {}; delta {}; window total with next spend {}; next spend: {}",
             tx_ctx.slot,
-            tx_ctx.slot - space.last_slot + 1,
-            space.last_spend,
+            self.delta_slot,
+            spent,
             tx_ctx.amount,
         );
-        let spent;
-        // check if the last spend is irrelevant (too old)
-        if tx_ctx.slot < space.last_slot + self.delta_slot {
-            spent = space.last_spend + tx_ctx.amount;
-        } else {
-            spent = tx_ctx.amount;
-        }
-        if self.max_spend <= spent {
+        // spending exactly max_spend is allowed
+        if self.max_spend < spent {
             return Err(TreasuryError::RuleRateLimiterCannotExceedSpendLimit.into());
         }
 
@@ -91,11 +90,11 @@ impl<'b> Rule<'b> for RateLimiter {
         Ok(())
     }
 
-    fn hash<'a>(&'a self, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
         let mut x = [0u8; std::mem::size_of::<RateLimiter>()];
         let mut cursor = std::io::Cursor::new(x.as_mut());
         self.serialize(&mut cursor)?;
         //msg!("_______+++++rule({})={:X?}",x.len(),&x);
-        return Ok(generic_hash(&index, &x, prev_hash));
+        generic_hash(version, self.id(), &index, &x, prev_hash)
     }
 }
@@ -0,0 +1,83 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_SECP256K1_SIGNATURE};
+use crate::sigverify::{has_secp256k1_signature, ETH_ADDRESS_SIZE};
+use crate::spend::{Recheck, SpendState, TransferContext};
+use crate::{nplog, RuleAddSecp256k1Signature, SpendProcessSecp256k1Signature};
+
+impl<'info> RuleAddSecp256k1Signature<'info> {
+    pub fn process(&mut self, eth_address: [u8; ETH_ADDRESS_SIZE]) -> ProgramResult {
+        let rule = Secp256k1Signature::new(eth_address, false);
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessSecp256k1Signature<'info> {
+    pub fn process(&mut self, eth_address: [u8; ETH_ADDRESS_SIZE]) -> ProgramResult {
+        let message = self.request.intent_message();
+        let has_signed = has_secp256k1_signature(&self.instructions, &eth_address, &message);
+        nplog!("secp256k1 signature found {}", has_signed);
+        let rule = Secp256k1Signature::new(eth_address, has_signed);
+        // see SpendProcessEd25519Signature
+        let nonce = self.request.intent_nonce();
+        self.request
+            .process_with_recheck(&rule, Recheck::IntentNonce { nonce })?;
+        Ok(())
+    }
+}
+
+/// Passes when a Secp256k1 program instruction in the same transaction carries a signature
+/// recovering to eth_address over the spend intent (see SpendRequest::intent_message).
+/// The precompile hashes the intent with keccak256 before recovering the address.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct Secp256k1Signature {
+    pub eth_address: [u8; ETH_ADDRESS_SIZE],
+    pub has_signed: bool,
+}
+
+// we only want to serialize eth_address to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct Secp256k1SignatureOnly {
+    pub eth_address: [u8; ETH_ADDRESS_SIZE],
+}
+
+impl Secp256k1Signature {
+    pub fn new(eth_address: [u8; ETH_ADDRESS_SIZE], has_signed: bool) -> Self {
+        Self {
+            eth_address,
+            has_signed,
+        }
+    }
+
+    pub fn for_serialization(&self) -> Secp256k1SignatureOnly {
+        Secp256k1SignatureOnly {
+            eth_address: self.eth_address,
+        }
+    }
+}
+
+impl<'b> Rule<'b> for Secp256k1Signature {
+    fn id(&self) -> u8 {
+        RULE_SECP256K1_SIGNATURE
+    }
+
+    fn process(&self, _state: &mut SpendState, _context: &TransferContext) -> Result<()> {
+        if !self.has_signed {
+            return Err(TreasuryError::RuleSecp256k1SignatureMissing.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<Secp256k1SignatureOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
@@ -49,7 +49,8 @@ impl<'info> SpendProcessSweep<'info>{
             self.required_destination.amount,
             min_bal,
         );
-        self.request.process(&rule)?;
+        // a sweep to any other account fails whatever its balance, so nothing is recorded for it
+        self.request.process_with_balance(&rule,&self.required_destination.key(),self.required_destination.amount)?;
 
         // we mark this spend request as a sweep so that the rate limit is not incremented
         self.request.context.is_sweep=true;
@@ -110,14 +111,14 @@ impl<'b> Rule<'b> for Sweep{
         Ok(())
     }
 
-    fn hash<'a>(&'a self,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
+    fn hash<'a>(&'a self,version: u8,index: u8,prev_hash: &'a[u8])->Result<[u8;HASH_BYTES]> {
         
         let mut x=[0u8;std::mem::size_of::<SweepOnly>()];
         let so =self.for_serialization();
         let mut cursor = std::io::Cursor::new(x.as_mut());
         so.serialize(&mut cursor)?;
         //msg!("_______+++++rule({})={:X?}",x.len(),&x);
-        return Ok(generic_hash(&index,&x,prev_hash))
+        generic_hash(version,self.id(),&index,&x,prev_hash)
     }
 
   
@@ -0,0 +1,98 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+
+use crate::errors::TreasuryError;
+use crate::rule::{generic_hash, Rule, RULE_TIME_WINDOW};
+use crate::spend::{Recheck, SpendState, TransferContext};
+use crate::{nplog, RuleAddTimeWindow, SpendProcessTimeWindow};
+
+impl<'info> RuleAddTimeWindow<'info> {
+    pub fn process(&mut self, valid_from: i64, valid_until: i64) -> ProgramResult {
+        let rule = TimeWindow::new(valid_from, valid_until, 0)?;
+        if self.accumulator.add(&rule).is_err() {
+            return Err(ProgramError::Custom(TreasuryError::RuleAddFail.into()));
+        }
+        Ok(())
+    }
+}
+
+impl<'info> SpendProcessTimeWindow<'info> {
+    pub fn process(&mut self, valid_from: i64, valid_until: i64) -> ProgramResult {
+        let rule = TimeWindow::new(valid_from, valid_until, self.clock.unix_timestamp)?;
+        // the window can close before the request is completed
+        self.request.process_with_recheck(
+            &rule,
+            Recheck::TimeWindow {
+                valid_from,
+                valid_until,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Passes while valid_from <= now < valid_until, where now is Clock::unix_timestamp
+/// at the time the rule is processed, and again when the spend request is completed.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct TimeWindow {
+    pub valid_from: i64,
+    pub valid_until: i64,
+    pub now: i64,
+}
+
+// we only want to serialize the window to do the hash
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct TimeWindowOnly {
+    pub valid_from: i64,
+    pub valid_until: i64,
+}
+
+impl TimeWindow {
+    pub fn new(valid_from: i64, valid_until: i64, now: i64) -> Result<Self> {
+        if valid_until <= valid_from {
+            return Err(TreasuryError::RuleTimeWindowEndsBeforeStart.into());
+        }
+        Ok(Self {
+            valid_from,
+            valid_until,
+            now,
+        })
+    }
+
+    pub fn for_serialization(&self) -> TimeWindowOnly {
+        TimeWindowOnly {
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+        }
+    }
+}
+
+impl<'b> Rule<'b> for TimeWindow {
+    fn id(&self) -> u8 {
+        RULE_TIME_WINDOW
+    }
+
+    fn process(&self, _state: &mut SpendState, _context: &TransferContext) -> Result<()> {
+        nplog!(
+            "time window {} <= {} < {}",
+            self.valid_from,
+            self.now,
+            self.valid_until
+        );
+        if self.now < self.valid_from {
+            return Err(TreasuryError::RuleTimeWindowNotStarted.into());
+        }
+        if self.valid_until <= self.now {
+            return Err(TreasuryError::RuleTimeWindowEnded.into());
+        }
+        Ok(())
+    }
+
+    fn hash<'a>(&'a self, version: u8, index: u8, prev_hash: &'a [u8]) -> Result<[u8; HASH_BYTES]> {
+        let mut x = [0u8; std::mem::size_of::<TimeWindowOnly>()];
+        let mut cursor = std::io::Cursor::new(x.as_mut());
+        self.for_serialization().serialize(&mut cursor)?;
+        generic_hash(version, self.id(), &index, &x, prev_hash)
+    }
+}
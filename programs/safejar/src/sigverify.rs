@@ -0,0 +1,139 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::secp256k1_program;
+use anchor_lang::solana_program::sysvar::instructions::load_instruction_at_checked;
+
+pub const SPEND_INTENT_PREFIX: &[u8] = b"safejar_spend_intent";
+
+/// SPEND_INTENT_PREFIX || delegation || destination_vault || mint || amount || nonce
+pub const SPEND_INTENT_SIZE: usize = 20 + 32 + 32 + 32 + 8 + 8;
+
+/// The message an off-chain approver signs.
+/// The nonce is the sequence number of this spend for this mint on the delegation,
+/// ie one more than SpendStateSlot.index, so each signed intent can be used for one spend only.
+pub fn spend_intent_message(
+    delegation: &Pubkey,
+    destination_vault: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    nonce: u64,
+) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(SPEND_INTENT_SIZE);
+    msg.extend_from_slice(SPEND_INTENT_PREFIX);
+    msg.extend_from_slice(delegation.as_ref());
+    msg.extend_from_slice(destination_vault.as_ref());
+    msg.extend_from_slice(mint.as_ref());
+    msg.extend_from_slice(&amount.to_le_bytes());
+    msg.extend_from_slice(&nonce.to_le_bytes());
+    msg
+}
+
+// every instruction in this transaction sent to the given program, with its position
+pub(crate) fn instructions_for_program(
+    instructions: &AccountInfo,
+    program_id: &Pubkey,
+) -> Vec<(usize, Instruction)> {
+    let mut list = Vec::new();
+    let mut i = 0;
+    while let Ok(ix) = load_instruction_at_checked(i, instructions) {
+        if ix.program_id == *program_id {
+            list.push((i, ix));
+        }
+        i += 1;
+    }
+    list
+}
+
+// the precompile has already checked every signature by the time we run;
+// we only need to find one over our message by our key.
+// Offsets that point into other instructions are skipped.
+const ED25519_OFFSETS_START: usize = 2;
+const ED25519_OFFSETS_SIZE: usize = 14;
+const ED25519_PUBKEY_SIZE: usize = 32;
+
+pub(crate) fn has_ed25519_signature(
+    instructions: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> bool {
+    instructions_for_program(instructions, &ed25519_program::ID)
+        .iter()
+        .any(|(_i, ix)| ed25519_data_has(&ix.data, signer, message))
+}
+
+fn ed25519_data_has(data: &[u8], signer: &Pubkey, message: &[u8]) -> bool {
+    let count = match data.first() {
+        Some(x) => *x as usize,
+        None => return false,
+    };
+    for i in 0..count {
+        let start = ED25519_OFFSETS_START + i * ED25519_OFFSETS_SIZE;
+        let offsets = match data.get(start..start + ED25519_OFFSETS_SIZE) {
+            Some(x) => x,
+            None => return false,
+        };
+        let read = |j: usize| u16::from_le_bytes([offsets[2 * j], offsets[2 * j + 1]]);
+        // signature_offset, signature_ix, pubkey_offset, pubkey_ix, message_offset, message_size, message_ix
+        let (pubkey_offset, message_offset, message_size) =
+            (read(2) as usize, read(4) as usize, read(5) as usize);
+        if read(1) != u16::MAX || read(3) != u16::MAX || read(6) != u16::MAX {
+            continue;
+        }
+        let pubkey = data.get(pubkey_offset..pubkey_offset + ED25519_PUBKEY_SIZE);
+        let signed = data.get(message_offset..message_offset + message_size);
+        if pubkey == Some(signer.as_ref()) && signed == Some(message) {
+            return true;
+        }
+    }
+    false
+}
+
+// same idea as Ed25519, except that the offsets name the instruction by position
+// and the key is a 20-byte Ethereum address
+const SECP256K1_OFFSETS_START: usize = 1;
+const SECP256K1_OFFSETS_SIZE: usize = 11;
+pub const ETH_ADDRESS_SIZE: usize = 20;
+
+pub(crate) fn has_secp256k1_signature(
+    instructions: &AccountInfo,
+    eth_address: &[u8; ETH_ADDRESS_SIZE],
+    message: &[u8],
+) -> bool {
+    instructions_for_program(instructions, &secp256k1_program::ID)
+        .iter()
+        .any(|(i, ix)| secp256k1_data_has(&ix.data, *i, eth_address, message))
+}
+
+fn secp256k1_data_has(
+    data: &[u8],
+    position: usize,
+    eth_address: &[u8; ETH_ADDRESS_SIZE],
+    message: &[u8],
+) -> bool {
+    let count = match data.first() {
+        Some(x) => *x as usize,
+        None => return false,
+    };
+    for i in 0..count {
+        let start = SECP256K1_OFFSETS_START + i * SECP256K1_OFFSETS_SIZE;
+        let offsets = match data.get(start..start + SECP256K1_OFFSETS_SIZE) {
+            Some(x) => x,
+            None => return false,
+        };
+        let read = |j: usize| u16::from_le_bytes([offsets[j], offsets[j + 1]]) as usize;
+        // signature_offset u16, signature_ix u8, eth_address_offset u16, eth_address_ix u8,
+        // message_offset u16, message_size u16, message_ix u8
+        let (address_offset, message_offset, message_size) = (read(3), read(6), read(8));
+        let ix_list = [offsets[2], offsets[5], offsets[10]];
+        if ix_list.iter().any(|x| *x as usize != position) {
+            continue;
+        }
+        let address = data.get(address_offset..address_offset + ETH_ADDRESS_SIZE);
+        let signed = data.get(message_offset..message_offset + message_size);
+        if address == Some(eth_address.as_ref()) && signed == Some(message) {
+            return true;
+        }
+    }
+    false
+}
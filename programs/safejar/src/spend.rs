@@ -5,32 +5,33 @@ use anchor_lang::prelude::*;
 use anchor_lang::solana_program::entrypoint::ProgramResult;
 use anchor_lang::solana_program::hash::hash;
 use anchor_spl::token::spl_token::native_mint::ID as sol_mint;
-use anchor_spl::token::{self, SyncNative, Transfer as TokenTransfer};
+use anchor_spl::token::{self, SyncNative, TokenAccount, Transfer as TokenTransfer};
 
+use crate::controller::Controller;
 use crate::delegate::Delegation;
 use crate::errors::TreasuryError;
 
 use crate::rule::{Rule, RuleAccumulator, ZERO_HASH};
+use crate::rulebudget::BudgetCap;
+use crate::rulecaller::is_called_by;
+use crate::ruleratelimiter::RateLimiter;
+use crate::ruletimewindow::TimeWindow;
+use crate::sigverify::spend_intent_message;
+use crate::spenddirect::load;
 use crate::{
-    nplog, tree, CompleteSpendRequestDirect, CreateSpendRequestDirect, PROGRAM_DELEGATION_SEED,
+    nplog, tree, ApproveSpendRequest, CancelSpendRequest, CompleteSpendRequestDirect,
+    CreateSpendRequestDirect, CreateSpendRequestPda, CreateSpendRequestToProgram,
+    PROGRAM_DELEGATION_SEED,
 };
 
 impl<'info> CreateSpendRequestDirect<'info> {
     pub fn process(&mut self, amount: u64, tree: Vec<u8>) -> ProgramResult {
-        if self.delegation_vault.mint == sol_mint {
-            token::sync_native(CpiContext::new(
-                self.token_program.to_account_info(),
-                SyncNative {
-                    account: self.destination_vault.to_account_info(),
-                },
-            ))?;
-            token::sync_native(CpiContext::new(
-                self.token_program.to_account_info(),
-                SyncNative {
-                    account: self.delegation_vault.to_account_info(),
-                },
-            ))?;
-        }
+        self.delegation.check_spendable(self.clock.slot)?;
+        sync_native_vaults(
+            &self.token_program.to_account_info(),
+            &self.delegation_vault,
+            &self.destination_vault,
+        )?;
         nplog!("np create - 1");
         let context = TransferContext::new(
             &self.destination_vault.mint,
@@ -45,8 +46,10 @@ impl<'info> CreateSpendRequestDirect<'info> {
         self.request.init(
             &self.delegation.key(),
             &self.delegation.state,
+            self.delegation.hash_version,
             &context,
             &tree,
+            self.delegation.max_request_age(),
         )?;
         nplog!("create - 3");
 
@@ -54,39 +57,212 @@ impl<'info> CreateSpendRequestDirect<'info> {
     }
 }
 
+impl<'info> CreateSpendRequestPda<'info> {
+    pub fn process(
+        &mut self,
+        amount: u64,
+        tree: Vec<u8>,
+        idempotency_key: [u8; 32],
+        receipt_bump: u8,
+    ) -> ProgramResult {
+        self.delegation.check_spendable(self.clock.slot)?;
+        sync_native_vaults(
+            &self.token_program.to_account_info(),
+            &self.delegation_vault,
+            &self.destination_vault,
+        )?;
+        let context = TransferContext::new(
+            &self.destination_vault.mint,
+            self.destination_owner.owner,
+            &self.linker.key(),
+            &self.delegation_vault.key(),
+            &self.destination_vault.key(),
+            &amount,
+            &self.clock.slot,
+        );
+        self.request.init(
+            &self.delegation.key(),
+            &self.delegation.state,
+            self.delegation.hash_version,
+            &context,
+            &tree,
+            self.delegation.max_request_age(),
+        )?;
+        self.request.idempotency_key = idempotency_key;
+        self.receipt
+            .init(receipt_bump, &self.request.key(), &self.request);
+
+        Ok(())
+    }
+}
+
+impl<'info> CreateSpendRequestToProgram<'info> {
+    pub fn process(&mut self, amount: u64, tree: Vec<u8>) -> ProgramResult {
+        self.delegation.check_spendable(self.clock.slot)?;
+        sync_native_vaults(
+            &self.token_program.to_account_info(),
+            &self.delegation_vault,
+            &self.destination_vault,
+        )?;
+        // the program that owns destination_owner, rather than the System Program
+        let context = TransferContext::new(
+            &self.destination_vault.mint,
+            self.destination_owner.owner,
+            &self.linker.key(),
+            &self.delegation_vault.key(),
+            &self.destination_vault.key(),
+            &amount,
+            &self.clock.slot,
+        );
+        self.request.init(
+            &self.delegation.key(),
+            &self.delegation.state,
+            self.delegation.hash_version,
+            &context,
+            &tree,
+            self.delegation.max_request_age(),
+        )?;
+
+        Ok(())
+    }
+}
+
+impl<'info> ApproveSpendRequest<'info> {
+    // amount and destination are repeated so that the approver's wallet shows what is being approved
+    pub fn process(&mut self, amount: u64, destination_vault: Pubkey) -> ProgramResult {
+        if self.request.context.amount != amount
+            || self.request.context.destination_vault != destination_vault
+        {
+            return Err(ProgramError::Custom(
+                TreasuryError::SpendRequestApprovalMismatch.into(),
+            ));
+        }
+        self.request.approve(&self.authorizer.key())?;
+        Ok(())
+    }
+}
+
+impl<'info> CancelSpendRequest<'info> {
+    pub fn process(&mut self) -> ProgramResult {
+        let authority = self.authority.key();
+        if authority == self.request.context.linker {
+            return Ok(());
+        }
+        let delegation = load::<Delegation>(&self.delegation)?;
+        let controller = load::<Controller>(&self.controller)?;
+        if self.request.delegation != self.delegation.key()
+            || delegation.controller != self.controller.key()
+            || authority != controller.owner
+        {
+            return Err(ProgramError::Custom(
+                TreasuryError::SpendRequestCancelNotAllowed.into(),
+            ));
+        }
+        if self.clock.slot < self.request.expiry_slot {
+            return Err(ProgramError::Custom(
+                TreasuryError::SpendRequestNotExpired.into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl<'info> CompleteSpendRequestDirect<'info> {
     pub fn process(&mut self) -> ProgramResult {
         nplog!("complete - 1");
+        // the delegation may have been paused or revoked since the request was created
+        let slot = Clock::get()?.slot;
+        self.delegation.check_spendable(slot)?;
+        if self.request.expiry_slot <= slot {
+            return Err(ProgramError::Custom(
+                TreasuryError::SpendRequestExpired.into(),
+            ));
+        }
+        self.check_balances()?;
+        // other requests may have spent from the delegation in the meantime
+        let now = Clock::get()?.unix_timestamp;
+        self.request
+            .recheck(&self.delegation.state, slot, now, &self.instructions)?;
         self.request.eval()?;
         nplog!("complete - 2");
         // do token spend
-        // amount
-        let transfer_instruction = TokenTransfer {
-            from: self.delegation_vault.to_account_info(),
-            to: self.destination_vault.to_account_info(),
-            authority: self.delegation.to_account_info(),
-        };
-        let bump_vector = self.delegation.bump.to_le_bytes();
-        // PROGRAM_HOLDING_SEED,controller.key().as_ref(),vault.key().as_ref()
-        let controller_id = self.delegation.controller;
-        //let vault_id = self.vault.key();
-        let inner = vec![
-            PROGRAM_DELEGATION_SEED,
-            controller_id.as_ref(),
-            self.delegation.rule_set_hash.as_ref(),
-            bump_vector.as_ref(),
-        ];
-        let outer = vec![inner.as_slice()];
-        let cpi_ctx = CpiContext::new_with_signer(
-            self.token_program.to_account_info(),
-            transfer_instruction,
-            &outer,
-        );
-        token::transfer(cpi_ctx, self.request.context.amount)?;
+        transfer_from_delegation(
+            &self.token_program.to_account_info(),
+            &self.delegation,
+            &self.delegation_vault.to_account_info(),
+            &self.destination_vault.to_account_info(),
+            self.request.context.amount,
+        )?;
 
         self.delegation.state.update(&self.request.context)?;
         Ok(())
     }
+
+    // the rules may have been processed in an earlier transaction. The balances must be exactly
+    // the ones recorded, so any transfer in between, even dust sent by anyone to the destination,
+    // makes the request fail; the linker then cancels it and processes the rules again
+    fn check_balances(&self) -> Result<()> {
+        for check in self.request.balance_check_list.iter() {
+            let live = if check.account == self.delegation_vault.key() {
+                self.delegation_vault.amount
+            } else if check.account == self.destination_vault.key() {
+                self.destination_vault.amount
+            } else {
+                return Err(TreasuryError::SpendRequestBalanceChanged.into());
+            };
+            if live != check.balance {
+                return Err(TreasuryError::SpendRequestBalanceChanged.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// wrapped SOL only shows up in the token amount after a sync
+pub(crate) fn sync_native_vaults<'info>(
+    token_program: &AccountInfo<'info>,
+    delegation_vault: &Account<'info, TokenAccount>,
+    destination_vault: &Account<'info, TokenAccount>,
+) -> Result<()> {
+    if delegation_vault.mint != sol_mint {
+        return Ok(());
+    }
+    for vault in [destination_vault, delegation_vault] {
+        token::sync_native(CpiContext::new(
+            token_program.clone(),
+            SyncNative {
+                account: vault.to_account_info(),
+            },
+        ))?;
+    }
+    Ok(())
+}
+
+/// Move amount out of a delegation vault; the delegation PDA signs.
+pub(crate) fn transfer_from_delegation<'info>(
+    token_program: &AccountInfo<'info>,
+    delegation: &Account<'info, Delegation>,
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let transfer_instruction = TokenTransfer {
+        from: from.clone(),
+        to: to.clone(),
+        authority: delegation.to_account_info(),
+    };
+    let bump_vector = delegation.bump.to_le_bytes();
+    // PROGRAM_HOLDING_SEED,controller.key().as_ref(),vault.key().as_ref()
+    let controller_id = delegation.controller;
+    let inner = vec![
+        PROGRAM_DELEGATION_SEED,
+        controller_id.as_ref(),
+        delegation.rule_set_hash.as_ref(),
+        bump_vector.as_ref(),
+    ];
+    let outer = vec![inner.as_slice()];
+    let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), transfer_instruction, &outer);
+    token::transfer(cpi_ctx, amount)
 }
 
 #[account]
@@ -94,25 +270,122 @@ pub struct SpendRequest {
     pub delegation: Pubkey,
     pub state: SpendState,
     pub context: TransferContext,
-    pub result: u64, // bitwise operation for max of 64 leaf nodes
+    pub result: Vec<u8>, // one bit per leaf node, see tree::set_result
     pub index: u8,
     pub count: u8,
     pub tree: Vec<u8>, // max size is 300B
     pub hash: [u8; 32],
+    pub version: u8, // copied from Delegation.hash_version
+    // authorizers who approved from their own transaction; see ApproveSpendRequest
+    pub approval_list: Vec<Pubkey>,
+    // the request can no longer be completed from this slot on
+    pub expiry_slot: u64,
+    // balances that rules looked at; completion fails if any of them moved
+    pub balance_check_list: Vec<BalanceCheck>,
+    // seed of a request made with create_spend_request_pda; zero otherwise
+    pub idempotency_key: [u8; 32],
+    // passed leaves that completion evaluates again; see process_with_recheck
+    pub recheck_list: Vec<LeafRecheck>,
+}
+
+/// Permanent record that an idempotency key was used to request a payment.
+/// It stays after the request completes or is cancelled.
+#[account]
+pub struct SpendReceipt {
+    pub bump: u8,
+    pub delegation: Pubkey,
+    pub idempotency_key: [u8; 32],
+    pub request: Pubkey,
+    pub destination_vault: Pubkey,
+    pub amount: u64,
+    pub slot: u64,
+}
+
+impl SpendReceipt {
+    pub fn init(&mut self, bump: u8, request_id: &Pubkey, request: &SpendRequest) {
+        self.bump = bump;
+        self.delegation = request.delegation;
+        self.idempotency_key = request.idempotency_key;
+        self.request = *request_id;
+        self.destination_vault = request.context.destination_vault;
+        self.amount = request.context.amount;
+        self.slot = request.context.slot;
+    }
+}
+
+/// What a leaf passed on, for the leaves whose answer can change after the rule is processed.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub enum Recheck {
+    TimeWindow { valid_from: i64, valid_until: i64 },
+    // these two are checked against the spend state of the delegation at completion
+    RateLimiter(RateLimiter),
+    BudgetCap(BudgetCap),
+    // a signed intent only holds while no other spend of the mint has completed
+    IntentNonce { nonce: u64 },
+    // the completing transaction has to come from the required program too
+    Caller { required_program: Pubkey, position: u8 },
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub struct LeafRecheck {
+    pub index: u8,
+    pub recheck: Recheck,
+}
+
+/// A token account balance as a rule saw it.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy)]
+pub struct BalanceCheck {
+    pub account: Pubkey,
+    pub balance: u64,
 }
 
 pub const TREE_MAX_SIZE: usize = 300;
+/// Completion can only see the source and destination vaults.
+pub const SPEND_REQUEST_MAX_BALANCE_CHECKS: usize = 2;
+/// About a day of slots; the default for Delegation::max_request_age.
+pub const SPEND_REQUEST_EXPIRY_SLOTS: u64 = 216_000;
 
 impl SpendRequest {
+    /// A request that only lives for one instruction; see SpendDirect.
+    pub fn new(
+        delegation: &Pubkey,
+        state: &SpendState,
+        version: u8,
+        context: &TransferContext,
+        tree: &Vec<u8>,
+    ) -> Result<Self> {
+        let mut request = Self {
+            delegation: *delegation,
+            state: SpendState { list: Vec::new() },
+            context: context.clone(),
+            result: Vec::new(),
+            index: 0,
+            count: 0,
+            tree: Vec::new(),
+            hash: RuleAccumulator::hash_init(),
+            version,
+            approval_list: Vec::new(),
+            expiry_slot: 0,
+            balance_check_list: Vec::new(),
+            idempotency_key: [0; 32],
+            recheck_list: Vec::new(),
+        };
+        request.init(delegation, state, version, context, tree, 0)?;
+        Ok(request)
+    }
+
     pub fn init(
         &mut self,
         delegation: &Pubkey,
         state: &SpendState,
+        version: u8,
         context: &TransferContext,
         tree: &Vec<u8>,
+        max_request_age: u64,
     ) -> Result<()> {
         msg!("s - 1");
         self.delegation = delegation.clone();
+        self.version = version;
         self.state = state.clone();
         //self.state.index+=1;
         let space = self.state.find(&context.mint)?;
@@ -121,19 +394,106 @@ impl SpendRequest {
         space.index += 1;
         // we check if the count has been incremented in the Delegation account in the final instruction
         self.index = 0;
-        let (_x, c) = tree::deserialize(tree)?;
+        let c = tree::validate(tree)?;
         msg!("s - 3");
 
         self.count = c;
+        self.result = vec![0; tree::result_size(c as usize)];
         self.tree = tree.clone();
         self.hash = RuleAccumulator::hash_init();
         self.hash_tree(&tree);
         msg!("s - 4");
         self.context = context.clone();
+        self.approval_list = Vec::new();
+        self.expiry_slot = context.slot.saturating_add(max_request_age);
+        self.balance_check_list = Vec::new();
+        self.idempotency_key = [0; 32];
+        self.recheck_list = Vec::new();
         msg!("s - 5");
         Ok(())
     }
 
+    pub fn approve(&mut self, authorizer: &Pubkey) -> Result<()> {
+        if self.is_approved(authorizer) {
+            return Ok(());
+        }
+        self.approval_list.push(*authorizer);
+        Ok(())
+    }
+
+    /// Bytes the request account has to grow by for authorizer to approve it.
+    pub fn approval_space(&self, authorizer: &Pubkey) -> usize {
+        if self.is_approved(authorizer) {
+            return 0;
+        }
+        std::mem::size_of::<Pubkey>()
+    }
+
+    pub fn is_approved(&self, authorizer: &Pubkey) -> bool {
+        self.approval_list.contains(authorizer)
+    }
+
+    /// Process a rule that read the balance of account, and remember the balance if the leaf
+    /// passed; a failed leaf does not count, so its balance does not have to hold.
+    pub fn process_with_balance(
+        &mut self,
+        rule: &dyn Rule,
+        account: &Pubkey,
+        balance: u64,
+    ) -> Result<()> {
+        let index = self.index;
+        self.process(rule)?;
+        if !tree::get_result(&self.result, &index) {
+            return Ok(());
+        }
+        self.record_balance(account, balance)
+    }
+
+    /// Remember a balance a rule was processed against, so that completion can check it again.
+    fn record_balance(&mut self, account: &Pubkey, balance: u64) -> Result<()> {
+        if let Some(check) = self
+            .balance_check_list
+            .iter()
+            .find(|x| x.account == *account)
+        {
+            // two rules saw the same account in different transactions
+            if check.balance != balance {
+                return Err(TreasuryError::SpendRequestBalanceChanged.into());
+            }
+            return Ok(());
+        }
+        if SPEND_REQUEST_MAX_BALANCE_CHECKS <= self.balance_check_list.len() {
+            return Err(TreasuryError::SpendRequestTooManyBalanceChecks.into());
+        }
+        self.balance_check_list.push(BalanceCheck {
+            account: *account,
+            balance,
+        });
+        Ok(())
+    }
+
+    /// The intent that must be signed for this request to pass an off-chain signature rule.
+    pub fn intent_message(&self) -> Vec<u8> {
+        spend_intent_message(
+            &self.delegation,
+            &self.context.destination_vault,
+            &self.context.mint,
+            self.context.amount,
+            self.intent_nonce(),
+        )
+    }
+
+    /// The nonce in intent_message.
+    pub fn intent_nonce(&self) -> u64 {
+        // init already advanced the sequence number on this copy of the spend state
+        self.state
+            .list
+            .iter()
+            .find(|x| x.mint == self.context.mint)
+            .map(|x| x.index)
+            .unwrap_or(0)
+    }
+
     // identical to RuleAccumulator
     pub fn hash_tree(&mut self, tree: &Vec<u8>) {
         let mut a = Vec::new();
@@ -145,7 +505,7 @@ impl SpendRequest {
 
     pub fn process(&mut self, rule: &dyn Rule) -> Result<()> {
         nplog!("sr - 1");
-        self.hash = rule.hash(self.index, &self.hash)?;
+        self.hash = rule.hash(self.version, self.index, &self.hash)?;
         nplog!("sr - 2");
         if self.count <= self.index {
             ////msg!("sr - 3");
@@ -153,25 +513,70 @@ impl SpendRequest {
         }
         nplog!("sr - 4");
         if rule.process(&mut self.state, &self.context).is_ok() {
-            nplog!(
-                "sr - 5 - index {} setting result {}",
-                self.index,
-                1 << self.index
-            );
-            self.result |= 1 << self.index;
+            nplog!("sr - 5 - index {} setting result", self.index);
+            tree::set_result(&mut self.result, &self.index);
         }
-        nplog!("sr - 6: {:064b}", self.result);
+        nplog!("sr - 6: {:02x?}", self.result);
         self.index += 1;
 
         Ok(())
     }
 
+    /// Process rule and, if its leaf passed, have completion evaluate the leaf again.
+    pub fn process_with_recheck(&mut self, rule: &dyn Rule, recheck: Recheck) -> Result<()> {
+        let index = self.index;
+        self.process(rule)?;
+        // each leaf is processed once, so there is room for one recheck per leaf
+        if !tree::get_result(&self.result, &index) {
+            return Ok(());
+        }
+        self.recheck_list.push(LeafRecheck { index, recheck });
+        Ok(())
+    }
+
+    /// Evaluate the leaves in recheck_list again, against the live spend state of the delegation
+    /// at slot and now, and the instructions of the completing transaction;
+    /// a leaf that no longer passes is cleared.
+    pub fn recheck(
+        &mut self,
+        live: &SpendState,
+        slot: u64,
+        now: i64,
+        instructions: &AccountInfo,
+    ) -> Result<()> {
+        let mut state = live.clone();
+        let mut context = self.context.clone();
+        context.slot = slot;
+        for leaf in self.recheck_list.iter() {
+            let passed = match &leaf.recheck {
+                Recheck::TimeWindow {
+                    valid_from,
+                    valid_until,
+                } => TimeWindow::new(*valid_from, *valid_until, now)?
+                    .process(&mut state, &context)
+                    .is_ok(),
+                Recheck::RateLimiter(rule) => rule.process(&mut state, &context).is_ok(),
+                Recheck::BudgetCap(rule) => rule.process(&mut state, &context).is_ok(),
+                Recheck::IntentNonce { nonce } => state
+                    .find(&context.mint)
+                    .map(|x| x.index.saturating_add(1) == *nonce)
+                    .unwrap_or(false),
+                Recheck::Caller {
+                    required_program,
+                    position,
+                } => is_called_by(instructions, required_program, *position),
+            };
+            if !passed {
+                nplog!("recheck - leaf {} no longer passes", leaf.index);
+                tree::clear_result(&mut self.result, &leaf.index);
+            }
+        }
+        Ok(())
+    }
+
     pub fn eval(&self) -> Result<()> {
         nplog!("eval - 1");
-        let mut index: u8 = 0;
-        let (root, _c) = tree::deserialize(&self.tree)?;
-        nplog!("eval - 2");
-        if !tree::evaluate(&mut index, root, &self.result) {
+        if !tree::evaluate(&self.tree, &self.result)? {
             return Err(TreasuryError::RuleEvalFalse.into());
         }
         nplog!("eval - 3");
@@ -194,11 +599,24 @@ impl SpendState {
         // find open slot into which we shall update the spend state
         let y = self.find(&txctx.mint)?;
 
+        // the delegation keeps the sequence number of completed spends; see SpendRequest::intent_message
+        y.index = y
+            .index
+            .checked_add(1)
+            .ok_or(TreasuryError::AmountOutOfRange)?;
+
+        // sweeps count against the lifetime total too; only rate limiting skips them
+        y.total_spent = y
+            .total_spent
+            .checked_add(txctx.amount)
+            .ok_or(TreasuryError::AmountOutOfRange)?;
+
         if !txctx.is_sweep {
             // not a sweep; so we update the spend state for this particular mint
             y.last_slot = txctx.slot;
             y.last_spend = txctx.amount;
             y.generic_score = 0;
+            y.record(txctx.slot, txctx.amount);
             nplog!("record rate limit: {} {}", y.last_slot, y.last_spend);
         } else {
             nplog!("why do we have a sweep?");
@@ -214,6 +632,20 @@ pub(crate) fn delegation_account_size(max_spend_state: u8) -> usize {
         + (max_spend_state as usize) * std::mem::size_of::<SpendStateSlot>();
 }
 
+// the request carries a copy of the delegation spend state
+pub(crate) fn spend_request_account_size(tree: &[u8], spend_state_len: usize) -> usize {
+    // every leaf takes at least one byte of the tree
+    let tree_len = tree.len();
+    // an invalid tree fails in process, whatever the size
+    let leaf_count = tree::validate(tree).unwrap_or(0) as usize;
+    8 + std::mem::size_of::<SpendRequest>()
+        + tree_len
+        + tree::result_size(tree_len.min(tree::TREE_MAX_LEAVES))
+        + spend_state_len * std::mem::size_of::<SpendStateSlot>()
+        + SPEND_REQUEST_MAX_BALANCE_CHECKS * std::mem::size_of::<BalanceCheck>()
+        + leaf_count * std::mem::size_of::<LeafRecheck>()
+}
+
 impl SpendState {
     pub fn new(size: u8) -> Self {
         let mut list = Vec::new();
@@ -277,6 +709,15 @@ impl SpendState {
     }
 }
 
+/// number of spends remembered per mint for rate limiting
+pub const SPEND_HISTORY_SIZE: usize = 8;
+
+#[derive(AnchorDeserialize, AnchorSerialize, Clone, Copy, Default)]
+pub struct SpendRecord {
+    pub slot: u64,
+    pub amount: u64,
+}
+
 #[derive(AnchorDeserialize, AnchorSerialize, Clone)]
 pub struct SpendStateSlot {
     pub mint: Pubkey,
@@ -284,6 +725,10 @@ pub struct SpendStateSlot {
     pub last_spend: u64,
     pub last_slot: u64,
     pub generic_score: u8,
+    // recent spends; an empty record has amount=0
+    pub history: [SpendRecord; SPEND_HISTORY_SIZE],
+    // everything ever spent of this mint
+    pub total_spent: u64,
 }
 
 impl SpendStateSlot {
@@ -294,7 +739,49 @@ impl SpendStateSlot {
             last_spend: 0,
             last_slot: 0,
             generic_score: u8::MAX - 1,
+            history: [SpendRecord::default(); SPEND_HISTORY_SIZE],
+            total_spent: 0,
+        }
+    }
+
+    /// Remember a spend for rate limiting.
+    /// When the history is full, the two oldest records are merged under the newer slot.
+    /// That overstates how recent the older amount is, so rate limits can only get stricter.
+    pub fn record(&mut self, slot: u64, amount: u64) {
+        if amount == 0 {
+            return;
+        }
+        let mut free = None;
+        for (i, r) in self.history.iter_mut().enumerate() {
+            if r.amount != 0 && r.slot == slot {
+                r.amount = r.amount.saturating_add(amount);
+                return;
+            }
+            if r.amount == 0 && free.is_none() {
+                free = Some(i);
+            }
+        }
+        let i = match free {
+            Some(i) => i,
+            None => {
+                self.history.sort_unstable_by_key(|r| r.slot);
+                let oldest = self.history[0].amount;
+                self.history[1].amount = self.history[1].amount.saturating_add(oldest);
+                0
+            }
+        };
+        self.history[i] = SpendRecord { slot, amount };
+    }
+
+    /// Total spent in the delta_slot slots leading up to and including slot.
+    pub fn spent_in_window(&self, slot: u64, delta_slot: u64) -> u64 {
+        let mut total: u64 = 0;
+        for r in self.history.iter() {
+            if r.amount != 0 && slot < r.slot.saturating_add(delta_slot) {
+                total = total.saturating_add(r.amount);
+            }
         }
+        total
     }
     pub fn is_blank(&self) -> bool {
         return self.mint == Pubkey::new_from_array(ZERO_HASH);
@@ -0,0 +1,104 @@
+//! Paying out of a delegation from another program.
+//!
+//! Build safejar with the `cpi` feature. Anchor then generates `safejar::cpi`, with one
+//! function per instruction and their account structs in `safejar::cpi::accounts`.
+//! The functions here wrap the calls a paying program needs. The linker is usually a PDA
+//! of the calling program, which signs with signer_seeds.
+//! They cannot live in `safejar::cpi` itself: Anchor owns that module and its generated code
+//! refers to `crate::cpi::accounts`, so nothing else can be declared at that path.
+//!
+//! There are two ways to pay:
+//! - spend_direct processes every rule and transfers in a single call; see RuleParam.
+//! - create_spend_request makes a request at spend_request_id(delegation, idempotency_key).
+//!   The rules are processed with the rule_process_* functions in `safejar::cpi`, or by
+//!   authorizers with approve_spend_request, and complete_spend_request does the
+//!   transfer. The request rent goes back to the linker when it closes.
+//!   The SpendReceipt at spend_receipt_id(delegation, idempotency_key) is never closed,
+//!   so a retried create_spend_request with the same key fails instead of paying twice.
+//!
+//! To make sure only the calling program can spend, add CallerProgram with CALLER_BY_CPI
+//! or an AuthorizationConstraint on the linker PDA to the rule set.
+
+use anchor_lang::prelude::*;
+
+#[cfg(feature = "cpi")]
+use crate::cpi;
+#[cfg(feature = "cpi")]
+use crate::spenddirect::RuleParam;
+use crate::{ID, PROGRAM_SPEND_RECEIPT_SEED, PROGRAM_SPEND_REQUEST_SEED};
+
+/// Address of the request that create_spend_request makes.
+pub fn spend_request_id(delegation: &Pubkey, idempotency_key: &[u8; 32]) -> Pubkey {
+    let x = [
+        PROGRAM_SPEND_REQUEST_SEED,
+        delegation.as_ref(),
+        idempotency_key.as_ref(),
+    ];
+    let (ans, _bump) = Pubkey::find_program_address(&x, &ID);
+    ans
+}
+
+/// Address of the receipt that marks idempotency_key as used.
+pub fn spend_receipt_id(delegation: &Pubkey, idempotency_key: &[u8; 32]) -> Pubkey {
+    let x = [
+        PROGRAM_SPEND_RECEIPT_SEED,
+        delegation.as_ref(),
+        idempotency_key.as_ref(),
+    ];
+    let (ans, _bump) = Pubkey::find_program_address(&x, &ID);
+    ans
+}
+
+/// Check the rules and transfer amount in one call.
+///
+/// # Errors
+///
+/// This function will return an error if the rules do not pass or the transfer fails.
+#[cfg(feature = "cpi")]
+pub fn spend_direct<'info>(
+    safejar_program: AccountInfo<'info>,
+    accounts: cpi::accounts::SpendDirect<'info>,
+    remaining_accounts: Vec<AccountInfo<'info>>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    tree: Vec<u8>,
+    param_list: Vec<RuleParam>,
+) -> Result<()> {
+    let ctx = CpiContext::new_with_signer(safejar_program, accounts, signer_seeds)
+        .with_remaining_accounts(remaining_accounts);
+    cpi::spend_direct(ctx, amount, tree, param_list)
+}
+
+/// Make a spend request at spend_request_id(delegation, idempotency_key).
+///
+/// # Errors
+///
+/// This function will return an error if idempotency_key was used before or the
+/// delegation cannot spend.
+#[cfg(feature = "cpi")]
+pub fn create_spend_request<'info>(
+    safejar_program: AccountInfo<'info>,
+    accounts: cpi::accounts::CreateSpendRequestPda<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    tree: Vec<u8>,
+    idempotency_key: [u8; 32],
+) -> Result<()> {
+    let ctx = CpiContext::new_with_signer(safejar_program, accounts, signer_seeds);
+    cpi::create_spend_request_pda(ctx, amount, tree, idempotency_key)
+}
+
+/// Transfer the funds once every rule of the request has been processed.
+///
+/// # Errors
+///
+/// This function will return an error if the rules do not pass or the request expired.
+#[cfg(feature = "cpi")]
+pub fn complete_spend_request<'info>(
+    safejar_program: AccountInfo<'info>,
+    accounts: cpi::accounts::CompleteSpendRequestDirect<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let ctx = CpiContext::new_with_signer(safejar_program, accounts, signer_seeds);
+    cpi::complete_spend_request_direct(ctx)
+}
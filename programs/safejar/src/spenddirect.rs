@@ -0,0 +1,296 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::entrypoint::ProgramResult;
+use anchor_lang::solana_program::hash::HASH_BYTES;
+use anchor_lang::solana_program::sysvar::instructions::ID as INSTRUCTIONS_ID;
+use anchor_spl::token::TokenAccount;
+
+use crate::addressbook::AddressBook;
+use crate::errors::TreasuryError;
+use crate::rule::Rule;
+use crate::ruleaddrbook::AddressBookConstraint;
+use crate::ruleallowlist::DestinationAllowlist;
+use crate::ruleauthconstr::AuthorizationConstraint;
+use crate::rulebudget::BudgetCap;
+use crate::rulecaller::{is_called_by, CallerProgram};
+use crate::ruleed25519::Ed25519Signature;
+use crate::rulemaxamt::AmountConstraint;
+use crate::rulemaxbal::BalanceConstraint;
+use crate::rulemultisig::MultisigConstraint;
+use crate::ruleprogconstr::ProgramConstraint;
+use crate::ruleratelimiter::RateLimiter;
+use crate::rulesecp256k1::Secp256k1Signature;
+use crate::rulesweep::Sweep;
+use crate::ruletimewindow::TimeWindow;
+use crate::sigverify::{has_ed25519_signature, has_secp256k1_signature, ETH_ADDRESS_SIZE};
+use crate::spend::{sync_native_vaults, transfer_from_delegation, SpendRequest, TransferContext};
+use crate::{hash_is_equal, nplog, SpendDirect};
+
+/// The parameters of one leaf of the rule tree, in the same order the rules were added.
+/// They carry the same values as the matching rule_process_* instruction.
+///
+/// Some rules take accounts from remaining_accounts, in the order of the parameters:
+/// AuthorizationConstraint takes the required authorizer, Multisig takes every authorizer
+/// in authorizer_list, Sweep takes the required destination token account,
+/// AddressBook takes the book, and Ed25519Signature, Secp256k1Signature and CallerProgram
+/// take the instructions sysvar. An authorizer counts when its account is a signer.
+#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
+pub enum RuleParam {
+    RateLimiter {
+        mint: Pubkey,
+        max_spend: u64,
+        delta_slot: u64,
+    },
+    AuthorizationConstraint {
+        required_authorizer: Pubkey,
+    },
+    ProgramConstraint {
+        required_program: Pubkey,
+    },
+    BalanceConstraint {
+        max_balance: u64,
+    },
+    Sweep {
+        min_balance: u64,
+    },
+    TimeWindow {
+        valid_from: i64,
+        valid_until: i64,
+    },
+    BudgetCap {
+        mint: Pubkey,
+        max_total: u64,
+    },
+    AmountConstraint {
+        mint: Pubkey,
+        max_amount: u64,
+    },
+    DestinationAllowlist {
+        root: [u8; HASH_BYTES],
+        proof: Vec<[u8; HASH_BYTES]>,
+    },
+    AddressBook,
+    Multisig {
+        threshold: u8,
+        authorizer_list: Vec<Pubkey>,
+    },
+    Ed25519Signature {
+        required_signer: Pubkey,
+    },
+    Secp256k1Signature {
+        eth_address: [u8; ETH_ADDRESS_SIZE],
+    },
+    CallerProgram {
+        required_program: Pubkey,
+        position: u8,
+    },
+}
+
+impl<'info> SpendDirect<'info> {
+    pub fn process(
+        &mut self,
+        amount: u64,
+        tree: Vec<u8>,
+        param_list: Vec<RuleParam>,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> ProgramResult {
+        self.delegation.check_spendable(self.clock.slot)?;
+        sync_native_vaults(
+            &self.token_program.to_account_info(),
+            &self.delegation_vault,
+            &self.destination_vault,
+        )?;
+        // the balance rules below read the synced amount
+        self.delegation_vault.reload()?;
+        let context = TransferContext::new(
+            &self.destination_vault.mint,
+            self.destination_owner.owner,
+            &self.linker.key(),
+            &self.delegation_vault.key(),
+            &self.destination_vault.key(),
+            &amount,
+            &self.clock.slot,
+        );
+        // the request never leaves this instruction, so there is no account to rent or close
+        let mut request = SpendRequest::new(
+            &self.delegation.key(),
+            &self.delegation.state,
+            self.delegation.hash_version,
+            &context,
+            &tree,
+        )?;
+
+        let mut account_iter = remaining_accounts.iter();
+        for param in param_list {
+            nplog!("spend direct - rule {}", request.index);
+            self.process_param(&mut request, param, &mut account_iter)?;
+        }
+        if !hash_is_equal(&request.hash, &self.delegation.rule_set_hash) {
+            return Err(ProgramError::Custom(
+                TreasuryError::SpendDirectRuleSetMismatch.into(),
+            ));
+        }
+        request.eval()?;
+
+        transfer_from_delegation(
+            &self.token_program.to_account_info(),
+            &self.delegation,
+            &self.delegation_vault.to_account_info(),
+            &self.destination_vault.to_account_info(),
+            amount,
+        )?;
+        self.delegation.state.update(&request.context)?;
+        Ok(())
+    }
+
+    // build the rule the same way the matching SpendProcess* instruction does
+    fn process_param<'a>(
+        &self,
+        request: &mut SpendRequest,
+        param: RuleParam,
+        account_iter: &mut impl Iterator<Item = &'a AccountInfo<'info>>,
+    ) -> Result<()>
+    where
+        'info: 'a,
+    {
+        let rule: Box<dyn Rule> = match param {
+            RuleParam::RateLimiter {
+                mint,
+                max_spend,
+                delta_slot,
+            } => Box::new(RateLimiter::new(&mint, max_spend, delta_slot)?),
+            RuleParam::AuthorizationConstraint {
+                required_authorizer,
+            } => {
+                let authorizer_info = next_account_for(account_iter, &required_authorizer)?;
+                let authorizer = authorizer_info.is_signer.then_some(required_authorizer);
+                Box::new(AuthorizationConstraint::new(
+                    &required_authorizer,
+                    authorizer,
+                ))
+            }
+            RuleParam::ProgramConstraint { required_program } => {
+                Box::new(ProgramConstraint::new(&required_program))
+            }
+            RuleParam::BalanceConstraint { max_balance } => Box::new(BalanceConstraint::new(
+                &self.delegation_vault.mint,
+                self.delegation_vault.amount,
+                max_balance,
+            )),
+            RuleParam::Sweep { min_balance } => {
+                let required_destination_info = next_account(account_iter)?;
+                let required_destination = load::<TokenAccount>(required_destination_info)?;
+                // we mark this spend as a sweep so that the rate limit is not incremented
+                request.context.is_sweep = true;
+                Box::new(Sweep::new(
+                    required_destination_info.key,
+                    &required_destination.mint,
+                    required_destination.amount,
+                    min_balance,
+                ))
+            }
+            RuleParam::TimeWindow {
+                valid_from,
+                valid_until,
+            } => Box::new(TimeWindow::new(
+                valid_from,
+                valid_until,
+                self.clock.unix_timestamp,
+            )?),
+            RuleParam::BudgetCap { mint, max_total } => Box::new(BudgetCap::new(&mint, max_total)?),
+            RuleParam::AmountConstraint { mint, max_amount } => {
+                Box::new(AmountConstraint::new(&mint, max_amount)?)
+            }
+            RuleParam::DestinationAllowlist { root, proof } => Box::new(DestinationAllowlist::new(
+                root,
+                &self.destination_vault.key(),
+                &self.destination_vault.owner,
+                proof,
+            )?),
+            RuleParam::AddressBook => {
+                let book_info = next_account(account_iter)?;
+                let book = load::<AddressBook>(book_info)?;
+                Box::new(AddressBookConstraint::new(
+                    book_info.key,
+                    &self.destination_vault.key(),
+                    book.lookup(&self.destination_vault.key()),
+                    book.lookup(&self.destination_vault.owner),
+                ))
+            }
+            RuleParam::Multisig {
+                threshold,
+                authorizer_list,
+            } => {
+                let mut signed = 0;
+                for authorizer in authorizer_list.iter() {
+                    if next_account_for(account_iter, authorizer)?.is_signer {
+                        signed += 1;
+                    }
+                }
+                Box::new(MultisigConstraint::new(threshold, authorizer_list, signed)?)
+            }
+            RuleParam::Ed25519Signature { required_signer } => {
+                let instructions = next_instructions(account_iter)?;
+                let message = request.intent_message();
+                let has_signed = has_ed25519_signature(instructions, &required_signer, &message);
+                Box::new(Ed25519Signature::new(&required_signer, has_signed))
+            }
+            RuleParam::Secp256k1Signature { eth_address } => {
+                let instructions = next_instructions(account_iter)?;
+                let message = request.intent_message();
+                let has_signed = has_secp256k1_signature(instructions, &eth_address, &message);
+                Box::new(Secp256k1Signature::new(eth_address, has_signed))
+            }
+            RuleParam::CallerProgram {
+                required_program,
+                position,
+            } => {
+                let instructions = next_instructions(account_iter)?;
+                let has_called = is_called_by(instructions, &required_program, position);
+                Box::new(CallerProgram::new(&required_program, position, has_called))
+            }
+        };
+        request.process(rule.as_ref())
+    }
+}
+
+fn next_account<'a, 'info>(
+    account_iter: &mut impl Iterator<Item = &'a AccountInfo<'info>>,
+) -> Result<&'a AccountInfo<'info>>
+where
+    'info: 'a,
+{
+    account_iter
+        .next()
+        .ok_or_else(|| TreasuryError::SpendDirectMissingAccount.into())
+}
+
+fn next_account_for<'a, 'info>(
+    account_iter: &mut impl Iterator<Item = &'a AccountInfo<'info>>,
+    key: &Pubkey,
+) -> Result<&'a AccountInfo<'info>>
+where
+    'info: 'a,
+{
+    let account = next_account(account_iter)?;
+    if account.key != key {
+        return Err(TreasuryError::SpendDirectWrongAccount.into());
+    }
+    Ok(account)
+}
+
+fn next_instructions<'a, 'info>(
+    account_iter: &mut impl Iterator<Item = &'a AccountInfo<'info>>,
+) -> Result<&'a AccountInfo<'info>>
+where
+    'info: 'a,
+{
+    next_account_for(account_iter, &INSTRUCTIONS_ID)
+}
+
+// the same owner and discriminator checks that Account::try_from makes
+pub(crate) fn load<T: AccountDeserialize + Owner>(info: &AccountInfo) -> Result<T> {
+    if *info.owner != T::owner() {
+        return Err(ErrorCode::AccountOwnedByWrongProgram.into());
+    }
+    T::try_deserialize(&mut &info.data.borrow()[..])
+}
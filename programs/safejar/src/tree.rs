@@ -6,6 +6,7 @@ use anchor_lang::error_code;
 use anchor_lang::solana_program::msg;
 
 use crate::nplog;
+use crate::spend::TREE_MAX_SIZE;
 
 #[error_code]
 pub enum BooleanTreeError {
@@ -17,14 +18,36 @@ pub enum BooleanTreeError {
     BadBoolean,
     #[msg("Index out of range")]
     IndexOutOfRange,
+    #[msg("Bad threshold")]
+    BadThreshold,
+    #[msg("Tree too deep")]
+    TooDeep,
+    #[msg("Tree too large")]
+    TooLarge,
+    #[msg("Leaf index used more than once")]
+    DuplicateLeaf,
+    #[msg("Leaf indexes skip a rule")]
+    LeafGap,
+    #[msg("Leaf index past the result bitmap")]
+    LeafOutOfRange,
+}
+
+/// The gate at an internal node.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gate {
+    Or,
+    And,
+    /// one child
+    Not,
+    /// true when at least k of the children are true
+    Threshold(u8),
 }
 
 #[derive(Clone)]
 pub struct Node {
     i: u8,
-    is_and: bool,
-    left: Option<Rc<RefCell<Node>>>,
-    right: Option<Rc<RefCell<Node>>>,
+    gate: Gate,
+    children: Vec<Rc<RefCell<Node>>>,
 }
 
 impl Node {
@@ -35,39 +58,75 @@ impl Node {
     ) -> Self {
         Self {
             i: NULL,
-            is_and,
-            left: Some(left.clone()),
-            right: Some(right.clone()),
+            gate: if is_and { Gate::And } else { Gate::Or },
+            children: vec![left.clone(), right.clone()],
+        }
+    }
+    pub fn new_not(child: &Rc<RefCell<Node>>) -> Self {
+        Self {
+            i: NULL,
+            gate: Gate::Not,
+            children: vec![child.clone()],
+        }
+    }
+    /// k of the children must be true
+    pub fn new_threshold(k: u8, children: &[Rc<RefCell<Node>>]) -> Self {
+        Self {
+            i: NULL,
+            gate: Gate::Threshold(k),
+            children: children.to_vec(),
         }
     }
     pub fn new() -> Self {
         Node {
             i: NULL,
-            is_and: true,
-            left: None,
-            right: None,
+            gate: Gate::And,
+            children: Vec::new(),
         }
     }
 
     pub fn set_i(&mut self, i: u8) {
         self.i = i;
-        self.is_and = false; // this value does not matter
-        self.left = None;
-        self.right = None;
+        self.gate = Gate::Or; // this value does not matter
+        self.children.clear();
     }
     pub fn set_and(&mut self) {
-        self.is_and = true;
+        self.gate = Gate::And;
     }
     pub fn set_or(&mut self) {
-        self.is_and = false;
+        self.gate = Gate::Or;
+    }
+    pub fn set_not(&mut self) {
+        self.gate = Gate::Not;
+    }
+    pub fn set_threshold(&mut self, k: u8) {
+        self.gate = Gate::Threshold(k);
     }
     pub fn set_left(&mut self, node: &Option<Rc<RefCell<Node>>>) {
-        self.i = NULL;
-        self.left = node.clone();
+        self.set_child(0, node);
     }
     pub fn set_right(&mut self, node: &Option<Rc<RefCell<Node>>>) {
+        self.set_child(1, node);
+    }
+    pub fn push_child(&mut self, node: &Rc<RefCell<Node>>) {
         self.i = NULL;
-        self.right = node.clone();
+        self.children.push(node.clone());
+    }
+    pub fn gate(&self) -> Gate {
+        self.gate
+    }
+
+    fn set_child(&mut self, position: usize, node: &Option<Rc<RefCell<Node>>>) {
+        self.i = NULL;
+        match node {
+            Some(x) => {
+                while self.children.len() <= position {
+                    self.children.push(x.clone());
+                }
+                self.children[position] = x.clone();
+            }
+            None => self.children.truncate(position),
+        }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -78,25 +137,64 @@ impl Node {
 
 pub const NULL: u8 = u8::MAX;
 
+/// Encoding version 0 (no header): a leaf is its rule index;
+/// an internal node is NULL, then 1 for AND or 0 for OR, then the left and right subtrees.
+///
+/// Encoding version 1 starts with TREE_V1 and adds gates after NULL:
+/// NOT_GATE then one subtree, or THRESHOLD_GATE, k, n, then n subtrees.
+/// Trees with only AND and OR are always written as version 0, so their bytes and the
+/// rule set hashes derived from them do not change.
+/// Leaf index TREE_V1 is reserved.
+pub const TREE_V1: u8 = u8::MAX - 1;
+pub const OR_GATE: u8 = 0;
+pub const AND_GATE: u8 = 1;
+pub const NOT_GATE: u8 = 2;
+pub const THRESHOLD_GATE: u8 = 3;
+
 pub fn serialize(root: Option<Rc<RefCell<Node>>>) -> Vec<u8> {
     let mut a = Vec::new();
+    if needs_v1(&root) {
+        a.push(TREE_V1);
+    }
     serialize_helper(root, &mut a);
     return a;
 }
 
+fn needs_v1(root: &Option<Rc<RefCell<Node>>>) -> bool {
+    match root {
+        Some(node) => {
+            let node = node.borrow();
+            if node.i != NULL {
+                return false;
+            }
+            match node.gate {
+                Gate::And | Gate::Or => node.children.iter().any(|c| needs_v1(&Some(c.clone()))),
+                Gate::Not | Gate::Threshold(_) => true,
+            }
+        }
+        None => false,
+    }
+}
+
 fn serialize_helper(root: Option<Rc<RefCell<Node>>>, wtr: &mut Vec<u8>) {
     match root {
         Some(node) => {
             let val = node.borrow().i;
             wtr.push(val);
             if val == NULL {
-                if node.borrow().is_and {
-                    wtr.push(1)
-                } else {
-                    wtr.push(0)
+                match node.borrow().gate {
+                    Gate::Or => wtr.push(OR_GATE),
+                    Gate::And => wtr.push(AND_GATE),
+                    Gate::Not => wtr.push(NOT_GATE),
+                    Gate::Threshold(k) => {
+                        wtr.push(THRESHOLD_GATE);
+                        wtr.push(k);
+                        wtr.push(node.borrow().children.len() as u8);
+                    }
+                }
+                for child in node.borrow().children.iter() {
+                    serialize_helper(Some(child.clone()), wtr);
                 }
-                serialize_helper(node.borrow().left.clone(), wtr);
-                serialize_helper(node.borrow().right.clone(), wtr);
             }
         }
         None => {
@@ -105,52 +203,193 @@ fn serialize_helper(root: Option<Rc<RefCell<Node>>>, wtr: &mut Vec<u8>) {
     }
 }
 
-fn get_result(result: &u64, index: &u8) -> bool {
-    nplog!("get_result - index {} result {:064b} shift", index, result,);
-    return 0 < *result & (1 << *index);
+/// Deepest nesting of gates that validate and evaluate will walk.
+/// Each level costs one Frame on the stack, so this bounds the stack at a few hundred bytes.
+pub const TREE_MAX_DEPTH: usize = 32;
+
+/// Leaf indexes stop below the reserved bytes TREE_V1 and NULL.
+pub const TREE_MAX_LEAVES: usize = TREE_V1 as usize;
+
+/// Bytes in the result bitmap of a tree with count leaves, one bit per leaf.
+pub fn result_size(count: usize) -> usize {
+    count.div_ceil(8)
 }
 
-pub(crate) fn evaluate(count: &u8, root: Option<Rc<RefCell<Node>>>, result: &u64) -> bool {
-    match root {
-        Some(x) => {
-            return evaluate_helper(count, x, result);
-        }
-        None => {
-            let i = 0;
-            return get_result(result, &i);
+/// Set the bit for leaf index in the result bitmap.
+pub fn set_result(result: &mut [u8], index: &u8) {
+    if let Some(x) = result.get_mut(*index as usize / 8) {
+        *x |= 1 << (index % 8);
+    }
+}
+
+/// Clear the bit for leaf index in the result bitmap.
+pub fn clear_result(result: &mut [u8], index: &u8) {
+    if let Some(x) = result.get_mut(*index as usize / 8) {
+        *x &= !(1 << (index % 8));
+    }
+}
+
+pub(crate) fn get_result(result: &[u8], index: &u8) -> bool {
+    nplog!("get_result - index {} result {:02x?}", index, result);
+    // an index past the bitmap is a leaf that never ran
+    result
+        .get(*index as usize / 8)
+        .is_some_and(|x| x & (1 << (index % 8)) != 0)
+}
+
+// a gate whose children are still being read
+#[derive(Clone, Copy)]
+struct Frame {
+    gate: Gate,
+    arity: u8,
+    seen: u8,
+    passed: u8,
+}
+
+impl Frame {
+    const EMPTY: Frame = Frame {
+        gate: Gate::And,
+        arity: 0,
+        seen: 0,
+        passed: 0,
+    };
+
+    fn value(&self) -> bool {
+        match self.gate {
+            Gate::And => self.passed == self.arity,
+            Gate::Or => 0 < self.passed,
+            Gate::Not => self.passed == 0,
+            Gate::Threshold(k) => k <= self.passed,
         }
     }
 }
 
-fn evaluate_helper(count: &u8, root: Rc<RefCell<Node>>, result: &u64) -> bool {
-    let node = root.borrow();
-    let i = node.i;
-    if i < NULL {
-        nplog!("tree eval - 1 - i {} result {:064b} ", i, result);
-        return get_result(result, &i);
-    } else {
-        let left = evaluate_helper(count, node.left.clone().unwrap(), result);
-        let right = evaluate_helper(count, node.right.clone().unwrap(), result);
-        nplog!(
-            "tree eval - 2 - i={} is_and {} left {} right {}",
-            i,
-            node.is_and,
-            left,
-            right
-        );
-        if node.is_and {
-            return left && right;
-        } else {
-            return left || right;
+struct Scan {
+    value: bool,
+    count: u8,
+    max: u8,
+}
+
+// Walk the encoded tree once, in order, without building nodes.
+// Leaves take their value from result; a gate is folded into its parent as soon as its
+// last child has been read, so the stack only ever holds the open gates on the current path.
+fn scan(data: &[u8], result: &[u8]) -> Result<Scan, BooleanTreeError> {
+    let mut stack = [Frame::EMPTY; TREE_MAX_DEPTH];
+    let mut depth = 0;
+    let is_v1 = data.first() == Some(&TREE_V1);
+    let mut pos = if is_v1 { 1 } else { 0 };
+    let mut count: u8 = 0;
+    let mut max: u8 = 0;
+    // one bit per possible leaf index
+    let mut seen = [0u8; 32];
+    let read = |pos: &mut usize| -> Result<u8, BooleanTreeError> {
+        let x = *data.get(*pos).ok_or(BooleanTreeError::FailedToRead)?;
+        *pos += 1;
+        Ok(x)
+    };
+    loop {
+        let i = read(&mut pos)?;
+        if i == NULL {
+            let (gate, arity) = match read(&mut pos).map_err(|_| BooleanTreeError::Unknown)? {
+                OR_GATE => (Gate::Or, 2),
+                AND_GATE => (Gate::And, 2),
+                NOT_GATE if is_v1 => (Gate::Not, 1),
+                THRESHOLD_GATE if is_v1 => {
+                    let k = read(&mut pos)?;
+                    let arity = read(&mut pos)?;
+                    if k == 0 || arity < k {
+                        return Err(BooleanTreeError::BadThreshold);
+                    }
+                    (Gate::Threshold(k), arity)
+                }
+                _ => return Err(BooleanTreeError::BadBoolean),
+            };
+            if TREE_MAX_DEPTH <= depth {
+                return Err(BooleanTreeError::TooDeep);
+            }
+            stack[depth] = Frame {
+                gate,
+                arity,
+                seen: 0,
+                passed: 0,
+            };
+            depth += 1;
+            continue;
+        }
+        if is_v1 && i == TREE_V1 {
+            return Err(BooleanTreeError::IndexOutOfRange);
+        }
+        if TREE_MAX_LEAVES <= i as usize {
+            nplog!("leaf {} out of range", i);
+            return Err(BooleanTreeError::LeafOutOfRange);
+        }
+        let (byte, bit) = (i as usize / 8, 1 << (i % 8));
+        if seen[byte] & bit != 0 {
+            nplog!("leaf {} used twice", i);
+            return Err(BooleanTreeError::DuplicateLeaf);
+        }
+        seen[byte] |= bit;
+        if max < i {
+            max = i;
+        }
+        count = count.saturating_add(1);
+        let mut value = get_result(result, &i);
+
+        // close every gate that this leaf completes
+        loop {
+            if depth == 0 {
+                if pos < data.len() {
+                    nplog!("index out of range");
+                    return Err(BooleanTreeError::IndexOutOfRange);
+                }
+                return Ok(Scan { value, count, max });
+            }
+            let frame = &mut stack[depth - 1];
+            frame.seen += 1;
+            if value {
+                frame.passed += 1;
+            }
+            if frame.seen < frame.arity {
+                break;
+            }
+            value = frame.value();
+            depth -= 1;
         }
     }
 }
 
+/// Check the encoding and return the number of leaves, without allocating.
+/// The tree must fit in TREE_MAX_SIZE bytes and TREE_MAX_DEPTH levels,
+/// and its leaves must be 0..count, each exactly once.
+pub fn validate(data: &[u8]) -> Result<u8, BooleanTreeError> {
+    if TREE_MAX_SIZE < data.len() {
+        return Err(BooleanTreeError::TooLarge);
+    }
+    let x = scan(data, &[])?;
+    // no leaf repeats, so fewer leaves than max + 1 means one was skipped
+    if x.count as usize != x.max as usize + 1 {
+        nplog!("count={} vs max={}", x.count, x.max);
+        return Err(BooleanTreeError::LeafGap);
+    }
+    Ok(x.count)
+}
+
+/// Evaluate the encoded tree against the result bitmap, without allocating or recursing.
+/// Bit i % 8 of byte i / 8 holds the result of leaf i.
+pub fn evaluate(data: &[u8], result: &[u8]) -> Result<bool, BooleanTreeError> {
+    Ok(scan(data, result)?.value)
+}
+
 pub fn deserialize(data: &Vec<u8>) -> Result<(Option<Rc<RefCell<Node>>>, u8), BooleanTreeError> {
+    validate(data)?;
     let mut cursor = Cursor::new(data.clone());
     let mut max = 0;
     let mut count = 0;
-    let ans = deserialize_helper(None, &mut cursor, &mut max, &mut count)?;
+    let is_v1 = data.first() == Some(&TREE_V1);
+    if is_v1 {
+        cursor.set_position(1);
+    }
+    let ans = deserialize_helper(None, &mut cursor, is_v1, &mut max, &mut count)?;
 
     nplog!("data len={} vs cursor={}", data.len(), cursor.position());
     if (cursor.position() as usize) < data.len() {
@@ -166,9 +405,18 @@ pub fn deserialize(data: &Vec<u8>) -> Result<(Option<Rc<RefCell<Node>>>, u8), Bo
     return Ok((ans, count));
 }
 
+fn read_byte(rdr: &mut Cursor<Vec<u8>>) -> Result<u8, BooleanTreeError> {
+    let mut buf: [u8; 1] = [0; 1];
+    match rdr.read_exact(&mut buf) {
+        Ok(_) => Ok(buf[0]),
+        Err(_e) => Err(BooleanTreeError::FailedToRead),
+    }
+}
+
 fn deserialize_helper(
     parent: Option<Rc<RefCell<Node>>>,
     rdr: &mut Cursor<Vec<u8>>,
+    is_v1: bool,
     max: &mut u8,
     count: &mut u8,
 ) -> Result<Option<Rc<RefCell<Node>>>, BooleanTreeError> {
@@ -183,48 +431,56 @@ fn deserialize_helper(
         }
     }
 
-    let mut buf: [u8; 1] = [0; 1];
-    match rdr.read_exact(&mut buf) {
-        Ok(_) => {
-            //nplog!("dh - 1 - n with i={}", n.borrow().i);
-            n.borrow_mut().i = buf[0];
-        }
-        Err(_e) => {
-            //nplog!("dh - 1 - failed to read");
-            return Err(BooleanTreeError::FailedToRead.into());
-        }
-    }
+    n.borrow_mut().i = read_byte(rdr)?;
     let current_value = n.borrow().i;
     if current_value == NULL {
         // nplog!("dh - 2 - i=null");
-        match rdr.read_exact(&mut buf) {
-            Ok(_) => {
-                nplog!("dh - 6");
-                if buf[0] == 0 {
-                    n.borrow_mut().is_and = false;
-                } else if buf[0] == 1 {
-                    n.borrow_mut().is_and = true;
-                } else {
-                    nplog!("dh - 7");
-                    return Err(BooleanTreeError::BadBoolean.into());
-                }
-            }
+        let gate = match read_byte(rdr) {
+            Ok(x) => x,
             Err(_e) => {
                 nplog!("dh - 8");
                 return Err(BooleanTreeError::Unknown.into());
             }
-        }
+        };
+        let arity = match gate {
+            OR_GATE => {
+                n.borrow_mut().gate = Gate::Or;
+                2
+            }
+            AND_GATE => {
+                n.borrow_mut().gate = Gate::And;
+                2
+            }
+            NOT_GATE if is_v1 => {
+                n.borrow_mut().gate = Gate::Not;
+                1
+            }
+            THRESHOLD_GATE if is_v1 => {
+                let k = read_byte(rdr)?;
+                let arity = read_byte(rdr)?;
+                if k == 0 || arity < k {
+                    nplog!("dh - bad threshold {} of {}", k, arity);
+                    return Err(BooleanTreeError::BadThreshold);
+                }
+                n.borrow_mut().gate = Gate::Threshold(k);
+                arity
+            }
+            _ => {
+                nplog!("dh - 7");
+                return Err(BooleanTreeError::BadBoolean);
+            }
+        };
 
-        let left = Rc::new(RefCell::new(Node::new()));
-        n.borrow_mut().left = Some(left.clone());
-        //nplog!("dh - 9 - left - parent={}", current_value);
-        deserialize_helper(Some(left), rdr, max, count)?;
-        let right = Rc::new(RefCell::new(Node::new()));
-        n.borrow_mut().right = Some(right.clone());
-        //nplog!("dh - 9 - right - parent={}", current_value);
-        deserialize_helper(Some(right), rdr, max, count)?;
+        for _ in 0..arity {
+            let child = Rc::new(RefCell::new(Node::new()));
+            n.borrow_mut().children.push(child.clone());
+            deserialize_helper(Some(child), rdr, is_v1, max, count)?;
+        }
         // nplog!("dh - 10 - finished n={}", current_value);
     } else {
+        if is_v1 && current_value == TREE_V1 {
+            return Err(BooleanTreeError::IndexOutOfRange);
+        }
         if *max < current_value {
             *max = current_value;
         }
@@ -20,6 +20,7 @@ use safejar::{
         CreateController, CreateRuleAccumulator, TransferToController as DataTransferToController,
         TransferToDelegation as DataTransferToDelegation,
     },
+    rule::accumulator_id,
 };
 
 use super::{
@@ -73,14 +74,20 @@ impl ControllerCreator {
         );
     }
 
-    pub fn accumulator_ix(&self, accumulator: &Keypair, tree: &Vec<u8>) -> Instruction {
+    pub fn accumulator_ix(&self, nonce: u64, tree: &[u8]) -> Instruction {
         return Instruction::new_with_bytes(
             safejar::ID,
-            CreateRuleAccumulator { tree: tree.clone() }.data().as_ref(),
+            CreateRuleAccumulator {
+                tree: tree.to_vec(),
+                nonce,
+            }
+            .data()
+            .as_ref(),
             vec![
                 AccountMeta::new(self.id.clone(), false),
-                AccountMeta::new(accumulator.pubkey().clone(), false),
-                AccountMeta::new(self.owner.pubkey().clone(), true),
+                AccountMeta::new(accumulator_id(&self.id, nonce), false),
+                AccountMeta::new(self.owner.pubkey(), true),
+                AccountMeta::new(self.owner.pubkey(), true),
                 AccountMeta::new(rent_id, false),
                 AccountMeta::new(system_program::ID, false),
                 AccountMeta::new(TokenProgramID, false),
@@ -133,9 +140,9 @@ impl ControllerCreator {
                     AccountMeta::new(mint.clone(), false),
                     AccountMeta::new(self.owner.pubkey(), true),
                     AccountMeta::new(fee_payer.clone(), true),
-                    AccountMeta::new(TokenProgramID, true),
-                    AccountMeta::new(spl_associated_token_account::ID, true),
-                    AccountMeta::new(system_program::ID, true),
+                    AccountMeta::new_readonly(TokenProgramID, false),
+                    AccountMeta::new_readonly(spl_associated_token_account::ID, false),
+                    AccountMeta::new_readonly(system_program::ID, false),
                 ],
             );
         }
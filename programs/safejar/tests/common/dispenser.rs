@@ -10,7 +10,7 @@ use rand::Rng;
 use solana_program::{
     hash::{Hash, HASH_BYTES},
     instruction::{AccountMeta, Instruction},
-    sysvar::{clock::ID as clock_id, rent::ID as rent_id},
+    sysvar::{clock::ID as clock_id, instructions::ID as instructions_id, rent::ID as rent_id},
 };
 use solana_program_test::{
     tokio::{self, sync::watch::Ref},
@@ -20,12 +20,20 @@ use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer, transaction
 use safejar::{
     self,
     controller::{controller_id, Controller},
-    delegate::delegation_id,
+    delegate::{delegation_id, Delegation, DelegationStatus},
     instruction::{
-        ApproveDelegation as DataApproveDelegation,
+        AbortAccumulator as DataAbortAccumulator, ApproveDelegation as DataApproveDelegation,
+        ApproveSpendRequest as DataApproveSpendRequest,
+        CancelSpendRequest as DataCancelSpendRequest, CloseDelegation as DataCloseDelegation,
         CompleteSpendRequestDirect as DataCompleteSpendRequestDirect,
         CreateRuleAccumulator as DataCreateRuleAccumulator,
-        CreateSpendRequestDirect as DataCreateSpendRequestDirect, Delegate as DataDelegate,
+        CreateSpendRequestDirect as DataCreateSpendRequestDirect,
+        CreateSpendRequestPda as DataCreateSpendRequestPda,
+        CreateSpendRequestToProgram as DataCreateSpendRequestToProgram, Delegate as DataDelegate,
+        SpendDirect as DataSpendDirect,
+        PauseDelegation as DataPauseDelegation, ResumeDelegation as DataResumeDelegation,
+        RevokeDelegation as DataRevokeDelegation,
+        SetMaxRequestAge as DataSetMaxRequestAge, MigrateDelegation as DataMigrateDelegation,
         RuleAddAuthorizationConstraint as DataRuleAddAuthorizationConstraint,
         RuleAddProgramConstraint as DataRuleAddProgramConstraint,
         RuleAddRateLimiter as DataRuleAddRateLimiter,
@@ -34,13 +42,15 @@ use safejar::{
         RuleProcessSweep as DataRuleProcessSweep,
     },
     nplog,
-    rule::{Rule, RuleAccumulator},
+    rule::{accumulator_id, Rule, RuleAccumulator, RULE_HASH_LATEST},
     ruleauthconstr::{AuthorizationConstraint, AuthorizationConstraintOnly},
     ruleprogconstr::ProgramConstraint,
     ruleratelimiter::RateLimiter,
     spend::{SpendRequest, SpendState, TransferContext},
+    spendcpi::{spend_receipt_id, spend_request_id},
+    spenddirect::RuleParam,
     tree::{deserialize, serialize, Node},
-    ApproveDelegation,
+    ApproveDelegation, PROGRAM_DELEGATION_SEED,
 };
 
 use super::{
@@ -57,6 +67,8 @@ pub struct Dispenser<'a> {
     max_token_track: u8,
     tree: Rc<RefCell<Node>>,
     rule_count: u8,
+    accumulator_nonce: u64,
+    hash_version: u8,
 }
 
 // this is a Rule, but also we add a function to get instructions
@@ -69,6 +81,14 @@ pub trait DispenserRule<'b> {
         linker: &Pubkey,
         keypair_list: &Vec<Keypair>,
     ) -> Instruction;
+    // instructions that must come before all the rule instructions, ie signature precompiles
+    fn pre_spend_ix(&self) -> Vec<Instruction> {
+        Vec::new()
+    }
+    // the spend_direct parameter for this rule and the remaining accounts it takes
+    fn spend_param(&self, _keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        unimplemented!("no spend_direct parameter for this rule")
+    }
 }
 
 impl<'a> Dispenser<'a> {
@@ -109,6 +129,8 @@ impl<'a> Dispenser<'a> {
             tree,
             rule_count,
             max_token_track,
+            accumulator_nonce: rand::random(),
+            hash_version: RULE_HASH_LATEST,
         });
     }
 
@@ -139,7 +161,7 @@ impl<'a> Dispenser<'a> {
         &self,
         linker: &Pubkey,
         ix_list: &mut Vec<Instruction>,
-    ) -> Result<Keypair, CustomError> {
+    ) -> Result<Pubkey, CustomError> {
         if !self.has_rule_set {
             println!("no rule set");
             return Err(CustomError::code::<std::io::Error>(
@@ -147,9 +169,8 @@ impl<'a> Dispenser<'a> {
                 "does not match".to_owned(),
             ));
         }
-        // this is an ephemeral key only used inside a single transaction
-        let accumulator_kp = Keypair::new();
-        let accumulator = accumulator_kp.pubkey();
+        // the accumulator only lives inside a single transaction
+        let accumulator = self.accumulator_id();
         if self.rule_list.len() as u8 != self.rule_count {
             println!(
                 "rule list count does not match: {} vs {}",
@@ -168,7 +189,29 @@ impl<'a> Dispenser<'a> {
         ix_list.push(self.inside_delegate(&accumulator, linker)?);
         ix_list.push(self.approve_delegation()?);
 
-        Ok(accumulator_kp)
+        Ok(accumulator)
+    }
+
+    // hash the rule set the way delegations made before RULE_HASH_V1 did
+    pub fn set_hash_version(&mut self, version: u8) {
+        self.hash_version = version;
+    }
+
+    pub fn accumulator_id(&self) -> Pubkey {
+        accumulator_id(&self.controller, self.accumulator_nonce)
+    }
+
+    pub fn abort_accumulator_ix(&self, linker: &Pubkey, authority: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataAbortAccumulator {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(self.accumulator_id(), false),
+                AccountMeta::new(*linker, false),
+                AccountMeta::new_readonly(*authority, true),
+            ],
+        )
     }
 
     pub fn delegation_id(&self) -> Result<Pubkey, CustomError> {
@@ -177,6 +220,34 @@ impl<'a> Dispenser<'a> {
         return Ok(delegation_id(&self.controller, &h));
     }
 
+    /// The delegation account that Delegate and ApproveDelegation would leave behind.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the rule set cannot be hashed.
+    pub fn approved_delegation(&self) -> Result<Delegation, CustomError> {
+        let rule_set_hash = self.hash()?;
+        let (_, bump) = Pubkey::find_program_address(
+            &[
+                PROGRAM_DELEGATION_SEED,
+                self.controller.as_ref(),
+                rule_set_hash.as_ref(),
+            ],
+            &safejar::ID,
+        );
+        Ok(Delegation {
+            bump,
+            controller: self.controller,
+            rule_set_count: self.rule_count,
+            rule_set_hash,
+            state: SpendState::new(self.max_token_track),
+            requested_slot: 0,
+            hash_version: self.hash_version,
+            status: DelegationStatus::Active,
+            max_request_age: 0,
+        })
+    }
+
     pub fn hash(&self) -> Result<[u8; 32], CustomError> {
         let root = self.tree.clone();
         let treedata = root.borrow().serialize();
@@ -193,6 +264,7 @@ impl<'a> Dispenser<'a> {
                 return Err(CustomError::new(CommonError::Unknown, err));
             }
         };
+        ra.version = self.hash_version;
         for r in &self.rule_list {
             match ra.add(r.rule().as_ref()) {
                 Ok(_) => {}
@@ -219,6 +291,32 @@ impl<'a> Dispenser<'a> {
         destination_owner: &Pubkey,
         mint: &Pubkey,
         amount: u64,
+    ) -> Result<Keypair, CustomError> {
+        let request_signer =
+            self.spend_create(ix_list, fee_payer, destination_owner, mint, amount)?;
+        self.spend_finish(
+            keypair_list,
+            ix_list,
+            &request_signer.pubkey(),
+            fee_payer,
+            destination_owner,
+            mint,
+        )?;
+        Ok(request_signer)
+    }
+
+    /// Only create the spend request, so that approvals can be sent before it is finished.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if .
+    pub fn spend_create(
+        &self,
+        ix_list: &mut Vec<Instruction>,
+        fee_payer: &Keypair,
+        destination_owner: &Pubkey,
+        mint: &Pubkey,
+        amount: u64,
     ) -> Result<Keypair, CustomError> {
         let request_signer = Keypair::new();
 
@@ -243,18 +341,187 @@ impl<'a> Dispenser<'a> {
             amount,
         )?);
 
+        Ok(request_signer)
+    }
+
+    /// Create a spend request at the PDA for idempotency_key, without a request signer.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if .
+    pub fn spend_create_pda(
+        &self,
+        ix_list: &mut Vec<Instruction>,
+        fee_payer: &Keypair,
+        destination_owner: &Pubkey,
+        mint: &Pubkey,
+        amount: u64,
+        idempotency_key: [u8; 32],
+    ) -> Result<Pubkey, CustomError> {
+        let delegation = self.delegation_id()?;
+        let request = spend_request_id(&delegation, &idempotency_key);
+        let delegation_vault =
+            anchor_spl::associated_token::get_associated_token_address(&delegation, mint);
+        let destination_vault =
+            anchor_spl::associated_token::get_associated_token_address(destination_owner, mint);
+        let tree = serialize(Some(self.tree.clone()));
+        ix_list.push(Instruction::new_with_bytes(
+            safejar::ID,
+            DataCreateSpendRequestPda {
+                amount,
+                tree,
+                idempotency_key,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(delegation, false),
+                AccountMeta::new(request, false),
+                AccountMeta::new(spend_receipt_id(&delegation, &idempotency_key), false),
+                AccountMeta::new(delegation_vault, false),
+                AccountMeta::new(destination_vault, false),
+                AccountMeta::new_readonly(*destination_owner, false),
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new(fee_payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(clock_id, false),
+                AccountMeta::new_readonly(TokenProgramID, false),
+                AccountMeta::new_readonly(associated_token::ID, false),
+            ],
+        ));
+        Ok(request)
+    }
+
+    /// Create a spend request to the ATA of a program owned destination_owner.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if .
+    pub fn spend_create_to_program(
+        &self,
+        ix_list: &mut Vec<Instruction>,
+        fee_payer: &Keypair,
+        destination_owner: &Pubkey,
+        mint: &Pubkey,
+        amount: u64,
+    ) -> Result<Keypair, CustomError> {
+        let request_signer = Keypair::new();
+        let delegation = self.delegation_id()?;
+        let delegation_vault =
+            anchor_spl::associated_token::get_associated_token_address(&delegation, mint);
+        let destination_vault =
+            anchor_spl::associated_token::get_associated_token_address(destination_owner, mint);
+        let tree = serialize(Some(self.tree.clone()));
+        ix_list.push(Instruction::new_with_bytes(
+            safejar::ID,
+            DataCreateSpendRequestToProgram { amount, tree }
+                .data()
+                .as_ref(),
+            vec![
+                AccountMeta::new_readonly(delegation, false),
+                AccountMeta::new(request_signer.pubkey(), true),
+                AccountMeta::new(delegation_vault, false),
+                AccountMeta::new(destination_vault, false),
+                AccountMeta::new_readonly(*destination_owner, false),
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new(fee_payer.pubkey(), true),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(clock_id, false),
+                AccountMeta::new_readonly(TokenProgramID, false),
+            ],
+        ));
+        Ok(request_signer)
+    }
+
+    /// Process the rules and complete a spend request made with spend_create.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if .
+    pub fn spend_finish(
+        &self,
+        keypair_list: &mut Vec<Keypair>,
+        ix_list: &mut Vec<Instruction>,
+        request: &Pubkey,
+        fee_payer: &Keypair,
+        destination_owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<(), CustomError> {
+        let delegation = self.delegation_id()?;
+        let delegation_vault =
+            anchor_spl::associated_token::get_associated_token_address(&delegation, mint);
+        let destination_vault =
+            anchor_spl::associated_token::get_associated_token_address(destination_owner, mint);
+
         for r in &self.rule_list {
-            ix_list.push(r.spend_ix(&request_signer.pubkey(), &fee_payer.pubkey(), keypair_list))
+            ix_list.append(&mut r.pre_spend_ix());
+        }
+        for r in &self.rule_list {
+            ix_list.push(r.spend_ix(request, &fee_payer.pubkey(), keypair_list))
         }
         ix_list.push(self.ix_spend_complete(
             &fee_payer.pubkey(),
-            &request_signer.pubkey(),
+            request,
             &destination_vault,
             &delegation,
             &delegation_vault,
         )?);
 
-        Ok(request_signer)
+        Ok(())
+    }
+
+    /// Process the rules and transfer in a single spend_direct instruction.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if .
+    pub fn spend_direct(
+        &self,
+        keypair_list: &Vec<Keypair>,
+        ix_list: &mut Vec<Instruction>,
+        fee_payer: &Keypair,
+        destination_owner: &Pubkey,
+        mint: &Pubkey,
+        amount: u64,
+    ) -> Result<(), CustomError> {
+        let delegation = self.delegation_id()?;
+        let delegation_vault =
+            anchor_spl::associated_token::get_associated_token_address(&delegation, mint);
+        let destination_vault =
+            anchor_spl::associated_token::get_associated_token_address(destination_owner, mint);
+
+        let mut param_list = Vec::new();
+        let mut accounts = vec![
+            AccountMeta::new(delegation, false),
+            AccountMeta::new(delegation_vault, false),
+            AccountMeta::new(destination_vault, false),
+            AccountMeta::new_readonly(*destination_owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(fee_payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(clock_id, false),
+            AccountMeta::new_readonly(TokenProgramID, false),
+            AccountMeta::new_readonly(associated_token::ID, false),
+        ];
+        for r in &self.rule_list {
+            ix_list.append(&mut r.pre_spend_ix());
+            let (param, mut remaining) = r.spend_param(keypair_list);
+            param_list.push(param);
+            accounts.append(&mut remaining);
+        }
+        ix_list.push(Instruction::new_with_bytes(
+            safejar::ID,
+            DataSpendDirect {
+                amount,
+                tree: serialize(Some(self.tree.clone())),
+                param_list,
+            }
+            .data()
+            .as_ref(),
+            accounts,
+        ));
+
+        Ok(())
     }
 
     fn ix_spend_request(
@@ -314,6 +581,7 @@ impl<'a> Dispenser<'a> {
                 AccountMeta::new(system_program::ID, false),
                 AccountMeta::new(TokenProgramID, false),
                 AccountMeta::new(fee_payer.clone(), true),
+                AccountMeta::new_readonly(instructions_id, false),
             ],
         ));
     }
@@ -322,12 +590,15 @@ impl<'a> Dispenser<'a> {
         let tree_data = serialize(Some(self.tree.clone()));
         return Instruction::new_with_bytes(
             safejar::ID,
-            DataCreateRuleAccumulator { tree: tree_data }
-                .data()
-                .as_ref(),
+            DataCreateRuleAccumulator {
+                tree: tree_data,
+                nonce: self.accumulator_nonce,
+            }
+            .data()
+            .as_ref(),
             vec![
                 AccountMeta::new(self.controller.clone(), false),
-                AccountMeta::new(accumulator.clone(), true),
+                AccountMeta::new(*accumulator, false),
                 AccountMeta::new(self.owner.clone(), true),
                 AccountMeta::new(linker.clone(), true),
                 AccountMeta::new(rent_id, false),
@@ -353,8 +624,8 @@ impl<'a> Dispenser<'a> {
             .as_ref(),
             vec![
                 AccountMeta::new(self.controller.clone(), false),
-                AccountMeta::new(delegation.clone(), false),
                 AccountMeta::new(accumulator.clone(), false),
+                AccountMeta::new(delegation, false),
                 AccountMeta::new(linker.clone(), true),
                 AccountMeta::new(self.owner.clone(), true),
                 AccountMeta::new(rent_id, false),
@@ -378,6 +649,133 @@ impl<'a> Dispenser<'a> {
             ],
         ));
     }
+
+    pub fn cancel_spend_ix(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        authority: &Pubkey,
+    ) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataCancelSpendRequest {}.data().as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(delegation, false),
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(*linker, false),
+                AccountMeta::new_readonly(*authority, true),
+                AccountMeta::new_readonly(clock_id, false),
+            ],
+        ))
+    }
+
+    pub fn close_ix(&self, linker: &Pubkey) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataCloseDelegation {}.data().as_ref(),
+            vec![
+                AccountMeta::new(self.controller, false),
+                AccountMeta::new(delegation, false),
+                AccountMeta::new_readonly(self.owner, true),
+                AccountMeta::new(*linker, true),
+                AccountMeta::new_readonly(rent_id, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        ))
+    }
+
+    pub fn pause_ix(&self) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataPauseDelegation {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(delegation, false),
+                AccountMeta::new_readonly(self.owner, true),
+            ],
+        ))
+    }
+
+    pub fn resume_ix(&self) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataResumeDelegation {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(delegation, false),
+                AccountMeta::new_readonly(self.owner, true),
+            ],
+        ))
+    }
+
+    pub fn revoke_ix(&self) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataRevokeDelegation {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(delegation, false),
+                AccountMeta::new_readonly(self.owner, true),
+            ],
+        ))
+    }
+
+    pub fn set_max_request_age_ix(&self, max_request_age: u64) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataSetMaxRequestAge { max_request_age }.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(self.controller, false),
+                AccountMeta::new(delegation, false),
+                AccountMeta::new_readonly(self.owner, true),
+            ],
+        ))
+    }
+
+    pub fn migrate_ix(&self, payer: &Pubkey) -> Result<Instruction, CustomError> {
+        let delegation = self.delegation_id()?;
+        Ok(Instruction::new_with_bytes(
+            safejar::ID,
+            DataMigrateDelegation {}.data().as_ref(),
+            vec![
+                AccountMeta::new(delegation, false),
+                AccountMeta::new(*payer, true),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        ))
+    }
+}
+
+pub fn approve_spend_ix(
+    request: &Pubkey,
+    authorizer: &Pubkey,
+    destination_owner: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let destination_vault =
+        anchor_spl::associated_token::get_associated_token_address(destination_owner, mint);
+    Instruction::new_with_bytes(
+        safejar::ID,
+        DataApproveSpendRequest {
+            amount,
+            destination_vault,
+        }
+        .data()
+        .as_ref(),
+        vec![
+            AccountMeta::new(*request, false),
+            AccountMeta::new(*authorizer, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ],
+    )
 }
 
 pub async fn do_spend<'a>(
@@ -421,6 +819,41 @@ pub async fn do_spend<'a>(
     }
 }
 
+pub async fn do_spend_direct<'a>(
+    context: &mut ProgramTestContext,
+    keypair_list: &mut Vec<Keypair>,
+    fee_payer: &Keypair,
+    dispenser: &Dispenser<'a>,
+    destination_owner: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+) -> Result<(), CustomError> {
+    update_blockhash(context).await.unwrap();
+    let mut ix_list = Vec::new();
+    dispenser.spend_direct(
+        keypair_list,
+        &mut ix_list,
+        fee_payer,
+        destination_owner,
+        mint,
+        amount,
+    )?;
+
+    keypair_list.push(fee_payer.insecure_clone());
+    let sl = SignerList::new(keypair_list);
+    let tx = Transaction::new_signed_with_payer(
+        &ix_list,
+        Some(&fee_payer.pubkey()),
+        &sl,
+        context.last_blockhash,
+    );
+
+    match context.banks_client.process_transaction(tx).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(CustomError::new(CommonError::Unknown, err)),
+    }
+}
+
 // create the delegation account
 pub async fn do_delegation<'a>(
     context: &mut ProgramTestContext,
@@ -430,13 +863,11 @@ pub async fn do_delegation<'a>(
 ) {
     update_blockhash(context).await.unwrap();
     let mut ix_list = Vec::new();
-    let accumulator_signer = dispenser
-        .delegate(&fee_payer.pubkey(), &mut ix_list)
-        .unwrap();
+    dispenser.delegate(&fee_payer.pubkey(), &mut ix_list).unwrap();
     let tx = Transaction::new_signed_with_payer(
         &ix_list,
         Some(&fee_payer.pubkey()),
-        &[&fee_payer, &ctr.owner, &accumulator_signer],
+        &[fee_payer, &ctr.owner],
         context.last_blockhash,
     );
 
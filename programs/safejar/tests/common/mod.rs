@@ -4,6 +4,15 @@ pub mod controller;
 pub mod dispenser;
 pub mod errors;
 pub mod rpc;
+pub mod ruleab;
+pub mod rulebc;
+pub mod rulecp;
+pub mod ruleamt;
+pub mod ruleal;
 pub mod ruleac;
+pub mod rulems;
+pub mod rulepc;
 pub mod rulerl;
+pub mod rulesig;
 pub mod ruleswp;
+pub mod ruletw;
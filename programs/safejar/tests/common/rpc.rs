@@ -18,8 +18,9 @@ use solana_program::{
 };
 use solana_program_test::{tokio, BanksClientError, ProgramTest, ProgramTestContext};
 use solana_sdk::{
-    account::{ReadableAccount, WritableAccount},
+    account::{AccountSharedData, ReadableAccount, WritableAccount},
     pubkey::Pubkey,
+    rent::Rent,
     signature::Keypair,
     signer::Signer,
     signers::Signers,
@@ -61,6 +62,29 @@ pub async fn fetch_delegation(
     return Ok(Some(x));
 }
 
+/// Write a safejar owned account directly, ie one left behind by an older version of the program.
+/// The rent comes out of funder so that the bank's total lamports do not change.
+pub async fn store_account(
+    context: &mut ProgramTestContext,
+    funder: &Pubkey,
+    address: &Pubkey,
+    data: &[u8],
+    space: usize,
+) {
+    let lamports = Rent::default().minimum_balance(space);
+    let mut funder_account = context
+        .banks_client
+        .get_account(*funder)
+        .await
+        .unwrap()
+        .unwrap();
+    funder_account.lamports -= lamports;
+    context.set_account(funder, &funder_account.into());
+    let mut account = AccountSharedData::new(lamports, space, &safejar::ID);
+    account.data_as_mut_slice()[..data.len()].copy_from_slice(data);
+    context.set_account(address, &account);
+}
+
 pub async fn token_balance(context: &mut ProgramTestContext, mint: &Pubkey, owner: &Pubkey) -> u64 {
     let address = get_associated_token_address(owner, mint);
     println!("looking up token balance for {} {}", owner, address);
@@ -0,0 +1,144 @@
+use std::{cell::Cell, rc::Rc};
+
+use anchor_lang::{system_program, InstructionData};
+use safejar::{
+    self,
+    addressbook::address_book_id,
+    controller::controller_id,
+    instruction::{
+        AddressBookAdd as DataAddressBookAdd, AddressBookRemove as DataAddressBookRemove,
+        CreateAddressBook as DataCreateAddressBook, RuleAddAddressBook as DataRuleAddAddressBook,
+        RuleProcessAddressBook as DataRuleProcessAddressBook,
+    },
+    rule::Rule,
+    ruleaddrbook::AddressBookConstraint as RAddressBookConstraint,
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+/// Address book owned by the controller of owner.
+pub struct AddressBook {
+    pub owner: Pubkey,
+    pub book_id: u32,
+    pub id: Pubkey,
+}
+
+impl AddressBook {
+    pub fn new(owner: &Pubkey, book_id: u32) -> Self {
+        Self {
+            owner: *owner,
+            book_id,
+            id: address_book_id(&controller_id(owner), book_id),
+        }
+    }
+
+    pub fn create_ix(&self, payer: &Pubkey, capacity: u16) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataCreateAddressBook {
+                book_id: self.book_id,
+                capacity,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(&self.owner), false),
+                AccountMeta::new(self.id, false),
+                AccountMeta::new_readonly(self.owner, true),
+                AccountMeta::new(*payer, true),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ],
+        )
+    }
+
+    pub fn add_ix(&self, address: &Pubkey, allowed: bool) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataAddressBookAdd {
+                address: *address,
+                allowed,
+            }
+            .data()
+            .as_ref(),
+            self.edit_accounts(),
+        )
+    }
+
+    pub fn remove_ix(&self, address: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataAddressBookRemove { address: *address }.data().as_ref(),
+            self.edit_accounts(),
+        )
+    }
+
+    fn edit_accounts(&self) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(controller_id(&self.owner), false),
+            AccountMeta::new(self.id, false),
+            AccountMeta::new_readonly(self.owner, true),
+        ]
+    }
+}
+
+/// Rule pointing at an address book.
+/// Set target to the owner being paid before each spend.
+#[derive(Clone)]
+pub struct AddressBookConstraint {
+    pub x: RAddressBookConstraint,
+    pub mint: Pubkey,
+    pub target: Rc<Cell<Pubkey>>,
+}
+
+impl AddressBookConstraint {
+    pub fn new(book: &Pubkey, mint: &Pubkey) -> Self {
+        Self {
+            x: RAddressBookConstraint::new(book, &Pubkey::default(), None, None),
+            mint: *mint,
+            target: Rc::new(Cell::new(Pubkey::default())),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for AddressBookConstraint {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddAddressBook {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+                AccountMeta::new_readonly(self.x.book, false),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        let destination_vault = anchor_spl::associated_token::get_associated_token_address(
+            &self.target.get(),
+            &self.mint,
+        );
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessAddressBook {}.data().as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(self.x.book, false),
+                AccountMeta::new_readonly(destination_vault, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+}
@@ -37,6 +37,7 @@ use safejar::{
     },
     ruleprogconstr::ProgramConstraint,
     ruleratelimiter::RateLimiter,
+    spenddirect::RuleParam,
     tree::Node,
     ApproveDelegation,
 };
@@ -125,4 +126,19 @@ impl<'b> DispenserRule<'b> for AuthorizationConstraint {
             );
         }
     }
+
+    fn spend_param(&self, keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        let will_sign = keypair_list
+            .iter()
+            .any(|kp| kp.pubkey() == self.x.required_authorizer);
+        (
+            RuleParam::AuthorizationConstraint {
+                required_authorizer: self.x.required_authorizer,
+            },
+            vec![AccountMeta::new_readonly(
+                self.x.required_authorizer,
+                will_sign,
+            )],
+        )
+    }
 }
@@ -0,0 +1,96 @@
+use std::{cell::Cell, rc::Rc};
+
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddDestinationAllowlist as DataRuleAddDestinationAllowlist,
+        RuleProcessDestinationAllowlist as DataRuleProcessDestinationAllowlist,
+    },
+    rule::Rule,
+    ruleallowlist::{
+        allowlist_proof, allowlist_root, DestinationAllowlist as RDestinationAllowlist,
+    },
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+/// Allowlist of destination owners.
+/// Set target to the owner being paid before each spend so that the right proof is sent.
+#[derive(Clone)]
+pub struct DestinationAllowlist {
+    pub x: RDestinationAllowlist,
+    pub owner_list: Vec<Pubkey>,
+    pub mint: Pubkey,
+    pub target: Rc<Cell<Pubkey>>,
+}
+
+impl DestinationAllowlist {
+    pub fn new(mint: &Pubkey, owner_list: &[Pubkey]) -> Self {
+        Self {
+            x: RDestinationAllowlist::new(
+                allowlist_root(owner_list),
+                &Pubkey::default(),
+                &Pubkey::default(),
+                Vec::new(),
+            )
+            .unwrap(),
+            owner_list: owner_list.to_vec(),
+            mint: *mint,
+            target: Rc::new(Cell::new(Pubkey::default())),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for DestinationAllowlist {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddDestinationAllowlist { root: self.x.root }
+                .data()
+                .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        let target = self.target.get();
+        // a stranger gets an empty proof, which fails unless the list has one entry
+        let proof = match self.owner_list.iter().position(|x| *x == target) {
+            Some(i) => allowlist_proof(&self.owner_list, i),
+            None => Vec::new(),
+        };
+        let destination_vault =
+            anchor_spl::associated_token::get_associated_token_address(&target, &self.mint);
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessDestinationAllowlist {
+                root: self.x.root,
+                proof,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(destination_vault, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+}
@@ -0,0 +1,81 @@
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddAmountConstraint as DataRuleAddAmountConstraint,
+        RuleProcessAmountConstraint as DataRuleProcessAmountConstraint,
+    },
+    rule::Rule,
+    rulemaxamt::AmountConstraint as RAmountConstraint,
+    spenddirect::RuleParam,
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+#[derive(Clone)]
+pub struct AmountConstraint {
+    pub x: RAmountConstraint,
+}
+
+impl AmountConstraint {
+    pub fn new(mint: &Pubkey, max_amount: u64) -> Self {
+        Self {
+            x: RAmountConstraint::new(mint, max_amount).unwrap(),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for AmountConstraint {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddAmountConstraint {
+                max_amount: self.x.max_amount,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+                AccountMeta::new_readonly(self.x.mint, false),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessAmountConstraint {
+                max_amount: self.x.max_amount,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(self.x.mint, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+
+    fn spend_param(&self, _keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        let param = RuleParam::AmountConstraint {
+            mint: self.x.mint,
+            max_amount: self.x.max_amount,
+        };
+        (param, Vec::new())
+    }
+}
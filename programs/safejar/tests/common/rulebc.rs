@@ -0,0 +1,72 @@
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddBudgetCap as DataRuleAddBudgetCap,
+        RuleProcessBudgetCap as DataRuleProcessBudgetCap,
+    },
+    rule::Rule,
+    rulebudget::BudgetCap as RBudgetCap,
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+#[derive(Clone)]
+pub struct BudgetCap {
+    pub x: RBudgetCap,
+}
+
+impl BudgetCap {
+    pub fn new(mint: &Pubkey, max_total: u64) -> Self {
+        Self {
+            x: RBudgetCap::new(mint, max_total).unwrap(),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for BudgetCap {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddBudgetCap {
+                max_total: self.x.max_total,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+                AccountMeta::new_readonly(self.x.mint, false),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessBudgetCap {
+                max_total: self.x.max_total,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(self.x.mint, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+}
@@ -0,0 +1,96 @@
+use std::{cell::RefCell, rc::Rc};
+
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddCallerProgram as DataRuleAddCallerProgram,
+        RuleProcessCallerProgram as DataRuleProcessCallerProgram,
+    },
+    rule::Rule,
+    rulecaller::CallerProgram as RCallerProgram,
+    spenddirect::RuleParam,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    sysvar::instructions::ID as instructions_id,
+};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+/// Set call to the instruction that goes in front of the rule instructions, if any.
+#[derive(Clone)]
+pub struct CallerProgram {
+    pub x: RCallerProgram,
+    pub call: Rc<RefCell<Option<Instruction>>>,
+}
+
+impl CallerProgram {
+    pub fn new(required_program: &Pubkey, position: u8) -> Self {
+        Self {
+            x: RCallerProgram::new(required_program, position, false),
+            call: Rc::new(RefCell::new(None)),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for CallerProgram {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddCallerProgram {
+                required_program: self.x.required_program,
+                position: self.x.position,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn pre_spend_ix(&self) -> Vec<Instruction> {
+        self.call.borrow().iter().cloned().collect()
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessCallerProgram {
+                required_program: self.x.required_program,
+                position: self.x.position,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(instructions_id, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+
+    fn spend_param(&self, _keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        (
+            RuleParam::CallerProgram {
+                required_program: self.x.required_program,
+                position: self.x.position,
+            },
+            vec![AccountMeta::new_readonly(instructions_id, false)],
+        )
+    }
+}
@@ -0,0 +1,97 @@
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddMultisig as DataRuleAddMultisig, RuleProcessMultisig as DataRuleProcessMultisig,
+    },
+    rule::Rule,
+    rulemultisig::MultisigConstraint as RMultisigConstraint,
+    spenddirect::RuleParam,
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+use super::dispenser::DispenserRule;
+
+#[derive(Clone)]
+pub struct MultisigConstraint {
+    pub x: RMultisigConstraint,
+}
+
+impl MultisigConstraint {
+    pub fn new(threshold: u8, authorizer_list: &[Pubkey]) -> Self {
+        Self {
+            x: RMultisigConstraint::new(threshold, authorizer_list.to_vec(), 0).unwrap(),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for MultisigConstraint {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddMultisig {
+                threshold: self.x.threshold,
+                authorizer_list: self.x.authorizer_list.clone(),
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    // every authorizer in keypair_list signs
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        let mut accounts = vec![
+            AccountMeta::new(*request, false),
+            AccountMeta::new_readonly(*linker, true),
+        ];
+        for kp in keypair_list {
+            if self.x.authorizer_list.contains(&kp.pubkey()) {
+                accounts.push(AccountMeta::new_readonly(kp.pubkey(), true));
+            }
+        }
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessMultisig {
+                threshold: self.x.threshold,
+                authorizer_list: self.x.authorizer_list.clone(),
+            }
+            .data()
+            .as_ref(),
+            accounts,
+        )
+    }
+
+    // every authorizer is passed in; those in keypair_list sign
+    fn spend_param(&self, keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        let accounts = self
+            .x
+            .authorizer_list
+            .iter()
+            .map(|a| {
+                let will_sign = keypair_list.iter().any(|kp| kp.pubkey() == *a);
+                AccountMeta::new_readonly(*a, will_sign)
+            })
+            .collect();
+        let param = RuleParam::Multisig {
+            threshold: self.x.threshold,
+            authorizer_list: self.x.authorizer_list.clone(),
+        };
+        (param, accounts)
+    }
+}
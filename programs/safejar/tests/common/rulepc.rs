@@ -0,0 +1,74 @@
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddProgramConstraint as DataRuleAddProgramConstraint,
+        RuleProcessProgramConstraint as DataRuleProcessProgramConstraint,
+    },
+    rule::Rule,
+    ruleprogconstr::ProgramConstraint as RProgramConstraint,
+    spenddirect::RuleParam,
+};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+#[derive(Clone)]
+pub struct ProgramConstraint {
+    pub x: RProgramConstraint,
+}
+
+impl ProgramConstraint {
+    pub fn new(program_id: &Pubkey) -> Self {
+        Self {
+            x: RProgramConstraint::new(program_id),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for ProgramConstraint {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddProgramConstraint {}.data().as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(self.x.program_id, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessProgramConstraint {}.data().as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(self.x.program_id, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+
+    fn spend_param(&self, _keypair_list: &Vec<Keypair>) -> (RuleParam, Vec<AccountMeta>) {
+        (
+            RuleParam::ProgramConstraint {
+                required_program: self.x.program_id,
+            },
+            Vec::new(),
+        )
+    }
+}
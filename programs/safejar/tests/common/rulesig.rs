@@ -0,0 +1,198 @@
+use std::{cell::RefCell, rc::Rc};
+
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddEd25519Signature as DataRuleAddEd25519Signature,
+        RuleAddSecp256k1Signature as DataRuleAddSecp256k1Signature,
+        RuleProcessEd25519Signature as DataRuleProcessEd25519Signature,
+        RuleProcessSecp256k1Signature as DataRuleProcessSecp256k1Signature,
+    },
+    rule::Rule,
+    ruleed25519::Ed25519Signature as REd25519Signature,
+    rulesecp256k1::Secp256k1Signature as RSecp256k1Signature,
+};
+use solana_program::{
+    ed25519_program,
+    instruction::{AccountMeta, Instruction},
+    sysvar::instructions::ID as instructions_id,
+};
+use solana_sdk::{
+    pubkey::Pubkey,
+    secp256k1_instruction::{construct_eth_pubkey, new_secp256k1_instruction},
+    signature::Keypair,
+    signer::Signer,
+};
+
+use super::dispenser::DispenserRule;
+
+/// Off-chain signer; set intent to the message to sign before each spend.
+#[derive(Clone)]
+pub struct Ed25519Signature {
+    pub x: REd25519Signature,
+    pub signer: Rc<Keypair>,
+    pub intent: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Ed25519Signature {
+    pub fn new(signer: &Keypair) -> Self {
+        Self {
+            x: REd25519Signature::new(&signer.pubkey(), false),
+            signer: Rc::new(signer.insecure_clone()),
+            intent: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+}
+
+/// Build an Ed25519 program instruction with the key, signature and message inline.
+pub fn ed25519_ix(signer: &Keypair, message: &[u8]) -> Instruction {
+    let signature = signer.sign_message(message);
+    let pubkey_offset: u16 = 2 + 14;
+    let signature_offset: u16 = pubkey_offset + 32;
+    let message_offset: u16 = signature_offset + 64;
+    let mut data = vec![1u8, 0u8];
+    for x in [
+        signature_offset,
+        u16::MAX,
+        pubkey_offset,
+        u16::MAX,
+        message_offset,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+    data.extend_from_slice(signer.pubkey().as_ref());
+    data.extend_from_slice(signature.as_ref());
+    data.extend_from_slice(message);
+    Instruction::new_with_bytes(ed25519_program::ID, &data, vec![])
+}
+
+impl<'b> DispenserRule<'b> for Ed25519Signature {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddEd25519Signature {
+                required_signer: self.x.required_signer,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn pre_spend_ix(&self) -> Vec<Instruction> {
+        vec![ed25519_ix(&self.signer, &self.intent.borrow())]
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessEd25519Signature {
+                required_signer: self.x.required_signer,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(instructions_id, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+}
+
+/// Ethereum key; set intent to the message to sign before each spend.
+#[derive(Clone)]
+pub struct Secp256k1Signature {
+    pub x: RSecp256k1Signature,
+    pub secret: libsecp256k1::SecretKey,
+    pub intent: Rc<RefCell<Vec<u8>>>,
+    // position of the secp256k1 instruction in the transaction
+    pub position: u8,
+}
+
+impl Secp256k1Signature {
+    pub fn new(position: u8) -> Self {
+        // libsecp256k1 wants an older rand, so pick the bytes ourselves
+        let secret = loop {
+            if let Ok(x) = libsecp256k1::SecretKey::parse(&rand::random()) {
+                break x;
+            }
+        };
+        let eth_address = construct_eth_pubkey(&libsecp256k1::PublicKey::from_secret_key(&secret));
+        Self {
+            x: RSecp256k1Signature::new(eth_address, false),
+            secret,
+            intent: Rc::new(RefCell::new(Vec::new())),
+            position,
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for Secp256k1Signature {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddSecp256k1Signature {
+                eth_address: self.x.eth_address,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn pre_spend_ix(&self) -> Vec<Instruction> {
+        let mut ix = new_secp256k1_instruction(&self.secret, &self.intent.borrow());
+        // the sdk assumes the instruction comes first in the transaction
+        for i in [3, 6, 11] {
+            ix.data[i] = self.position;
+        }
+        vec![ix]
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessSecp256k1Signature {
+                eth_address: self.x.eth_address,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(instructions_id, false),
+                AccountMeta::new_readonly(*linker, true),
+            ],
+        )
+    }
+}
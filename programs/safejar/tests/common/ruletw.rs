@@ -0,0 +1,77 @@
+use anchor_lang::InstructionData;
+use safejar::{
+    self,
+    controller::controller_id,
+    instruction::{
+        RuleAddTimeWindow as DataRuleAddTimeWindow,
+        RuleProcessTimeWindow as DataRuleProcessTimeWindow,
+    },
+    rule::Rule,
+    ruletimewindow::TimeWindow as RTimeWindow,
+};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    sysvar::clock::ID as clock_id,
+};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+
+use super::dispenser::DispenserRule;
+
+#[derive(Clone)]
+pub struct TimeWindow {
+    pub x: RTimeWindow,
+}
+
+impl TimeWindow {
+    pub fn new(valid_from: i64, valid_until: i64) -> Self {
+        Self {
+            x: RTimeWindow::new(valid_from, valid_until, 0).unwrap(),
+        }
+    }
+}
+
+impl<'b> DispenserRule<'b> for TimeWindow {
+    fn rule<'a>(&self) -> Box<dyn Rule<'a>> {
+        // now is irrelevant here
+        Box::new(self.x.clone())
+    }
+
+    fn add_ix<'a>(&self, accumulator: &Pubkey, owner: &Pubkey) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleAddTimeWindow {
+                valid_from: self.x.valid_from,
+                valid_until: self.x.valid_until,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new_readonly(controller_id(owner), false),
+                AccountMeta::new(*accumulator, false),
+                AccountMeta::new_readonly(*owner, true),
+            ],
+        )
+    }
+
+    fn spend_ix<'a>(
+        &self,
+        request: &Pubkey,
+        linker: &Pubkey,
+        _keypair_list: &Vec<Keypair>,
+    ) -> Instruction {
+        Instruction::new_with_bytes(
+            safejar::ID,
+            DataRuleProcessTimeWindow {
+                valid_from: self.x.valid_from,
+                valid_until: self.x.valid_until,
+            }
+            .data()
+            .as_ref(),
+            vec![
+                AccountMeta::new(*request, false),
+                AccountMeta::new_readonly(*linker, true),
+                AccountMeta::new_readonly(clock_id, false),
+            ],
+        )
+    }
+}
@@ -3,8 +3,8 @@
 use std::{cell::RefCell, rc::Rc};
 
 use anchor_lang::{
-    accounts::program, prelude::borsh::de, system_program, AccountDeserialize, AnchorDeserialize,
-    InstructionData, ToAccountMetas,
+    error::ErrorCode, AccountDeserialize, AccountSerialize, AnchorDeserialize, AnchorSerialize,
+    Discriminator,
 };
 use anchor_spl::{
     associated_token::get_associated_token_address,
@@ -28,11 +28,16 @@ use solana_sdk::{
 use safejar::{
     self,
     controller::{controller_id, Controller},
-    delegate::Delegation as BDelegation,
+    delegate::{
+        delegation_id, Delegation as BDelegation, DelegationStatus, LegacyDelegation,
+        LegacySpendStateSlot,
+    },
+    errors::TreasuryError,
     instruction::CreateController,
     nplog,
-    rule::Rule,
-    ruleauthconstr::AuthorizationConstraintOnly,
+    rule::{Rule, RuleAccumulator, RULE_HASH_V0, RULE_HASH_V1},
+    ruleauthconstr::{AuthorizationConstraint, AuthorizationConstraintOnly},
+    ruleprogconstr::ProgramConstraint,
     ruleratelimiter::RateLimiter,
     tree::{serialize, Node},
 };
@@ -49,7 +54,7 @@ use crate::common::{
     basic::update_blockhash,
     dispenser::{do_delegation, do_spend, Dispenser},
     errors::CommonError,
-    rpc::{fetch_delegation, token_balance},
+    rpc::{fetch_delegation, store_account, token_balance},
     ruleac, rulerl, ruleswp,
 };
 
@@ -389,6 +394,420 @@ fn f02_2_make_tree() -> Rc<RefCell<Node>> {
     return join1;
 }
 
+/// Several small spends inside one window add up against the rate limit.
+///
+/// # Panics
+///
+/// Panics if the rate limiter only looks at the last spend.
+#[tokio::test]
+async fn f02_3_rate_limiter_accumulates() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr: ControllerCreator = prepare_controller(&mut context, &fee_payer, &cb).await;
+
+    let tree_data = serialize(Some(f02_1_make_tree()));
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &tree_data).unwrap();
+    let max_spend: u64 = 900_000;
+    let rl = Box::new(rulerl::RateLimiter {
+        x: RateLimiter {
+            mint: cb.id,
+            max_spend,
+            delta_slot: 10_000,
+        },
+    });
+    dispenser.rule_add2(rl).unwrap();
+    let authorizer1 = Keypair::new();
+    let ac1 = Box::new(ruleac::AuthorizationConstraint::new(
+        AuthorizationConstraintOnly {
+            required_authorizer: authorizer1.pubkey(),
+        },
+    ));
+    dispenser.rule_add2(ac1).unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+
+    let delegation_id = dispenser.delegation_id().unwrap();
+    cb.issue(&mut context, &fee_payer, &ctr.id, 2 * max_spend)
+        .await
+        .unwrap();
+    ctr.transfer(&mut context, true, &fee_payer, &cb.id, &delegation_id, 2 * max_spend)
+        .await
+        .unwrap();
+
+    let destination_owner = Keypair::new();
+    // three spends of a third each reach the limit exactly, which is allowed
+    for _ in 0..3 {
+        let mut keypair_list = vec![authorizer1.insecure_clone()];
+        do_spend(
+            &mut context,
+            &mut keypair_list,
+            &fee_payer,
+            &dispenser,
+            &destination_owner.pubkey(),
+            &cb.id,
+            max_spend / 3,
+        )
+        .await
+        .unwrap();
+    }
+    let mut keypair_list = vec![authorizer1.insecure_clone()];
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        1,
+    )
+    .await
+    {
+        Ok(_) => panic!("spend succeeded past the rate limit"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+/// A delegation can only be made from an accumulator that has every rule of its tree,
+/// and an unfinished accumulator can be aborted to get the rent back.
+///
+/// # Panics
+///
+/// Panics if a delegation is created with a rule missing.
+#[tokio::test]
+async fn f02_4_accumulator_must_be_complete() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr: ControllerCreator = prepare_controller(&mut context, &fee_payer, &cb).await;
+
+    let tree_data = serialize(Some(f02_1_make_tree()));
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &tree_data).unwrap();
+    let rl = Box::new(rulerl::RateLimiter {
+        x: RateLimiter {
+            mint: cb.id,
+            max_spend: 1_000,
+            delta_slot: 10_000,
+        },
+    });
+    dispenser.rule_add2(rl).unwrap();
+    let ac1 = Box::new(ruleac::AuthorizationConstraint::new(
+        AuthorizationConstraintOnly {
+            required_authorizer: Keypair::new().pubkey(),
+        },
+    ));
+    dispenser.rule_add2(ac1).unwrap();
+    dispenser.rule_stop().unwrap();
+
+    // create accumulator, add rule 0, add rule 1, delegate, approve
+    let mut ix_list = Vec::new();
+    let accumulator = dispenser
+        .delegate(&fee_payer.pubkey(), &mut ix_list)
+        .unwrap();
+    // leave an unfinished accumulator behind
+    send_tx(
+        &mut context,
+        &ix_list[0..2],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+
+    // delegate at the address of the partial hash, so that the seeds match
+    let account = context
+        .banks_client
+        .get_account(accumulator)
+        .await
+        .unwrap()
+        .unwrap();
+    let partial = RuleAccumulator::try_deserialize(&mut account.data.as_slice()).unwrap();
+    let mut delegate_ix = ix_list[3].clone();
+    delegate_ix.accounts[2].pubkey = delegation_id(&ctr.id, &partial.hash);
+    match send_tx(
+        &mut context,
+        &[delegate_ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    {
+        Ok(_) => panic!("delegated with a rule missing"),
+        Err(err) => {
+            let code = format!(
+                "{:#x}",
+                u32::from(TreasuryError::RuleAccumulatorIncomplete)
+            );
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+    let stranger = Keypair::new();
+    airdrop(&mut context, &stranger.pubkey(), 100_000_000)
+        .await
+        .unwrap();
+    let ix = dispenser.abort_accumulator_ix(&fee_payer.pubkey(), &stranger.pubkey());
+    match send_tx(&mut context, &[ix], &stranger.pubkey(), &[&stranger]).await {
+        Ok(_) => panic!("a stranger aborted the accumulator"),
+        Err(err) => {
+            let code = format!(
+                "{:#x}",
+                u32::from(TreasuryError::RuleAccumulatorAbortNotAllowed)
+            );
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+    let ix = dispenser.abort_accumulator_ix(&fee_payer.pubkey(), &fee_payer.pubkey());
+    send_tx(&mut context, &[ix], &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+    let account = context.banks_client.get_account(accumulator).await.unwrap();
+    assert!(account.is_none(), "accumulator {} is still open", accumulator);
+
+    // the same nonce can be used again
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+}
+
+/// Rules with identical serializations used to share a leaf hash.
+///
+/// # Panics
+///
+/// Panics if the V1 hash does not commit to the rule id, or if V0 changed.
+#[test]
+fn f02_5_rule_hash_commits_to_rule_id() {
+    let key = Keypair::new().pubkey();
+    let prev_hash = [0u8; HASH_BYTES];
+    let pc = ProgramConstraint::new(&key);
+    let ac = AuthorizationConstraint::new(&key, None);
+
+    let pc_v0 = pc.hash(RULE_HASH_V0, 0, &prev_hash).unwrap();
+    let ac_v0 = ac.hash(RULE_HASH_V0, 0, &prev_hash).unwrap();
+    assert_eq!(pc_v0, ac_v0, "V0 is the original format and must not change");
+
+    let pc_v1 = pc.hash(RULE_HASH_V1, 0, &prev_hash).unwrap();
+    let ac_v1 = ac.hash(RULE_HASH_V1, 0, &prev_hash).unwrap();
+    assert_ne!(pc_v1, ac_v1, "V1 must tell the two rules apart");
+    assert_ne!(pc_v0, pc_v1);
+}
+
+/// A delegation whose rule set was hashed before RULE_HASH_V1 can still spend.
+///
+/// # Panics
+///
+/// Panics if the V0 delegation cannot spend.
+#[tokio::test]
+async fn f02_6_v0_delegation_still_verifies() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr: ControllerCreator = prepare_controller(&mut context, &fee_payer, &cb).await;
+
+    let tree_data = serialize(Some(f02_1_make_tree()));
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &tree_data).unwrap();
+    let rl = Box::new(rulerl::RateLimiter {
+        x: RateLimiter {
+            mint: cb.id,
+            max_spend: 1_000_000,
+            delta_slot: 1_000,
+        },
+    });
+    dispenser.rule_add2(rl).unwrap();
+    let authorizer = Keypair::new();
+    let ac = Box::new(ruleac::AuthorizationConstraint::new(
+        AuthorizationConstraintOnly {
+            required_authorizer: authorizer.pubkey(),
+        },
+    ));
+    dispenser.rule_add2(ac).unwrap();
+    dispenser.rule_stop().unwrap();
+    let v1_hash = dispenser.hash().unwrap();
+
+    // Delegate only makes V1 delegations, so write the V0 one directly
+    dispenser.set_hash_version(RULE_HASH_V0);
+    assert_ne!(v1_hash, dispenser.hash().unwrap());
+    let delegation = dispenser.approved_delegation().unwrap();
+    let mut data = Vec::new();
+    delegation.try_serialize(&mut data).unwrap();
+    let delegation_id = dispenser.delegation_id().unwrap();
+    store_account(
+        &mut context,
+        &fee_payer.pubkey(),
+        &delegation_id,
+        &data,
+        data.len(),
+    )
+    .await;
+
+    let amount: u64 = 100_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(&mut context, true, &fee_payer, &cb.id, &delegation_id, amount)
+        .await
+        .unwrap();
+
+    let destination_owner = Keypair::new();
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &cb.id, &destination_owner.pubkey()).await,
+        amount
+    );
+}
+
+/// A delegation stored before spend state slots kept a history must be migrated before it can spend.
+#[tokio::test]
+async fn f02_7_migrate_legacy_delegation() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr: ControllerCreator = prepare_controller(&mut context, &fee_payer, &cb).await;
+
+    let tree_data = serialize(Some(f02_1_make_tree()));
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &tree_data).unwrap();
+    let rl = Box::new(rulerl::RateLimiter {
+        x: RateLimiter {
+            mint: cb.id,
+            max_spend: 1_000_000,
+            delta_slot: 1_000,
+        },
+    });
+    dispenser.rule_add2(rl).unwrap();
+    let authorizer = Keypair::new();
+    let ac = Box::new(ruleac::AuthorizationConstraint::new(
+        AuthorizationConstraintOnly {
+            required_authorizer: authorizer.pubkey(),
+        },
+    ));
+    dispenser.rule_add2(ac).unwrap();
+    dispenser.rule_stop().unwrap();
+    // delegations from before the layout change were all V0
+    dispenser.set_hash_version(RULE_HASH_V0);
+
+    let delegation = dispenser.approved_delegation().unwrap();
+    let legacy = LegacyDelegation {
+        bump: delegation.bump,
+        controller: delegation.controller,
+        rule_set_count: delegation.rule_set_count,
+        rule_set_hash: delegation.rule_set_hash,
+        state: delegation
+            .state
+            .list
+            .iter()
+            .map(|x| LegacySpendStateSlot {
+                mint: x.mint,
+                index: x.index,
+                last_spend: x.last_spend,
+                last_slot: x.last_slot,
+                generic_score: x.generic_score,
+            })
+            .collect(),
+        requested_slot: 0,
+        hash_version: RULE_HASH_V0,
+        status: DelegationStatus::Pending,
+        max_request_age: 0,
+    };
+    let mut data = BDelegation::DISCRIMINATOR.to_vec();
+    legacy.serialize(&mut data).unwrap();
+    let delegation_id = dispenser.delegation_id().unwrap();
+    store_account(
+        &mut context,
+        &fee_payer.pubkey(),
+        &delegation_id,
+        &data,
+        data.len(),
+    )
+    .await;
+
+    let amount: u64 = 100_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    // nothing that reads the delegation works until it is migrated
+    match ctr
+        .transfer(&mut context, true, &fee_payer, &cb.id, &delegation_id, amount)
+        .await
+    {
+        Ok(_) => panic!("funded a delegation in the old layout"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(ErrorCode::AccountDidNotDeserialize));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    send_tx(
+        &mut context,
+        &[dispenser.migrate_ix(&fee_payer.pubkey()).unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    .unwrap();
+    let migrated = fetch_delegation(&mut context, &delegation_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(migrated.rule_set_hash, delegation.rule_set_hash);
+    assert_eq!(migrated.state.list.len(), delegation.state.list.len());
+
+    // a second migration has nothing to do
+    match send_tx(
+        &mut context,
+        &[dispenser.migrate_ix(&fee_payer.pubkey()).unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    {
+        Ok(_) => panic!("migrated a delegation twice"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::DelegationAlreadyMigrated));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    ctr.transfer(&mut context, true, &fee_payer, &cb.id, &delegation_id, amount)
+        .await
+        .unwrap();
+    let destination_owner = Keypair::new();
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        amount / 2,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        token_balance(&mut context, &cb.id, &destination_owner.pubkey()).await,
+        amount / 2
+    );
+}
+
 async fn prepare_controller(
     context: &mut ProgramTestContext,
     fee_payer: &Keypair,
@@ -0,0 +1,1279 @@
+//#![cfg(feature = "test-sbf")]
+
+use std::{cell::RefCell, rc::Rc};
+
+use anchor_lang::AccountDeserialize;
+use safejar::{
+    self,
+    delegate::DelegationStatus,
+    errors::TreasuryError,
+    ruleauthconstr::AuthorizationConstraintOnly,
+    ruleratelimiter::RateLimiter,
+    spend::{SpendReceipt, SPEND_REQUEST_EXPIRY_SLOTS},
+    spendcpi::{spend_receipt_id, spend_request_id},
+    tree::{serialize, Node},
+};
+use solana_program_test::{tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{clock::Clock, pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+pub mod common;
+use common::{
+    basic::{airdrop, send_tx},
+    centralbank::CentralBank,
+    controller::ControllerCreator,
+    dispenser::{approve_spend_ix, do_delegation, do_spend, Dispenser, DispenserRule},
+    rpc::fetch_delegation,
+    ruleac, ruleamt, rulebc, rulerl, ruleswp, ruletw,
+};
+
+/// Pause, resume and revoke a delegation with a single authorization constraint.
+///
+/// # Panics
+///
+/// Panics if a spend succeeds while the delegation is paused or revoked.
+#[tokio::test]
+async fn f03_1_delegation_lifecycle() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    let authorizer = Keypair::new();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: authorizer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let d = fetch_delegation(&mut context, &delegation_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(d.status, DelegationStatus::Active);
+
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new();
+
+    // paused
+    send_tx(
+        &mut context,
+        &[dispenser.pause_ix().unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        amount / 10,
+    )
+    .await
+    {
+        Ok(_) => panic!("spend succeeded on a paused delegation"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::DelegationPaused));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    // resumed
+    send_tx(
+        &mut context,
+        &[dispenser.resume_ix().unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        amount / 10,
+    )
+    .await
+    .unwrap();
+
+    // revoked; resume must not bring it back
+    send_tx(
+        &mut context,
+        &[dispenser.revoke_ix().unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+    if send_tx(
+        &mut context,
+        &[dispenser.resume_ix().unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .is_ok()
+    {
+        panic!("resumed a revoked delegation");
+    }
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        amount / 10,
+    )
+    .await
+    {
+        Ok(_) => panic!("spend succeeded on a revoked delegation"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::DelegationRevoked));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    // the controller owner can still pull the remaining funds back
+    ctr.transfer(&mut context, false, &fee_payer, &cb.id, &delegation_id, 0)
+        .await
+        .unwrap();
+}
+
+/// The authorizer approves from their own transaction, after the linker created the request.
+///
+/// # Panics
+///
+/// Panics if an approval for a different amount is accepted.
+#[tokio::test]
+async fn f03_2_async_approval() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let authorizer = Keypair::new();
+    for payer in [&fee_payer, &authorizer] {
+        airdrop(&mut context, &payer.pubkey(), 10 * 100_000_000)
+            .await
+            .unwrap();
+    }
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: authorizer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // the linker creates the request on its own
+    let destination_owner = Keypair::new().pubkey();
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+
+    // the authorizer must approve what the request actually says
+    match send_tx(
+        &mut context,
+        &[approve_spend_ix(
+            &request.pubkey(),
+            &authorizer.pubkey(),
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+        )],
+        &authorizer.pubkey(),
+        &[&authorizer],
+    )
+    .await
+    {
+        Ok(_) => panic!("approved the wrong amount"),
+        Err(err) => {
+            let code = format!(
+                "{:#x}",
+                u32::from(TreasuryError::SpendRequestApprovalMismatch)
+            );
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    // approvals from anyone else must not leave the authorizer without room
+    for _ in 0..10 {
+        let stranger = Keypair::new();
+        airdrop(&mut context, &stranger.pubkey(), 100_000_000)
+            .await
+            .unwrap();
+        send_tx(
+            &mut context,
+            &[approve_spend_ix(
+                &request.pubkey(),
+                &stranger.pubkey(),
+                &destination_owner,
+                &cb.id,
+                amount,
+            )],
+            &stranger.pubkey(),
+            &[&stranger],
+        )
+        .await
+        .unwrap();
+    }
+    send_tx(
+        &mut context,
+        &[approve_spend_ix(
+            &request.pubkey(),
+            &authorizer.pubkey(),
+            &destination_owner,
+            &cb.id,
+            amount,
+        )],
+        &authorizer.pubkey(),
+        &[&authorizer],
+    )
+    .await
+    .unwrap();
+
+    // the linker finishes without the authorizer signing
+    let mut keypair_list = Vec::new();
+    let mut ix_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+}
+
+/// An abandoned request is closed and its rent refunded to the linker,
+/// by the linker at any time or by the controller owner after it expires.
+///
+/// # Panics
+///
+/// Panics if the controller owner can cancel a request that has not expired.
+#[tokio::test]
+async fn f03_3_cancel_spend_request() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: Keypair::new().pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let mut request_list = Vec::new();
+    for _ in 0..2 {
+        let mut ix_list = Vec::new();
+        let request = dispenser
+            .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+            .unwrap();
+        send_tx(
+            &mut context,
+            &ix_list,
+            &fee_payer.pubkey(),
+            &[&fee_payer, &request],
+        )
+        .await
+        .unwrap();
+        request_list.push(request.pubkey());
+    }
+
+    let stranger = Keypair::new();
+    for (authority, expected) in [
+        (&ctr.owner, TreasuryError::SpendRequestNotExpired),
+        (&stranger, TreasuryError::SpendRequestCancelNotAllowed),
+    ] {
+        let ix = dispenser
+            .cancel_spend_ix(&request_list[0], &fee_payer.pubkey(), &authority.pubkey())
+            .unwrap();
+        match send_tx(
+            &mut context,
+            &[ix],
+            &fee_payer.pubkey(),
+            &[&fee_payer, authority],
+        )
+        .await
+        {
+            Ok(_) => panic!("{} cancelled the request", authority.pubkey()),
+            Err(err) => {
+                let code = format!("{:#x}", u32::from(expected));
+                assert!(err.to_string().contains(&code), "wrong error: {}", err);
+            }
+        }
+    }
+
+    // the linker does not have to wait
+    let ix = dispenser
+        .cancel_spend_ix(&request_list[0], &fee_payer.pubkey(), &fee_payer.pubkey())
+        .unwrap();
+    send_tx(&mut context, &[ix], &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+
+    let slot = context.banks_client.get_root_slot().await.unwrap();
+    context
+        .warp_to_slot(slot + SPEND_REQUEST_EXPIRY_SLOTS + 1)
+        .unwrap();
+    let ix = dispenser
+        .cancel_spend_ix(&request_list[1], &fee_payer.pubkey(), &ctr.owner.pubkey())
+        .unwrap();
+    send_tx(
+        &mut context,
+        &[ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+
+    for request in request_list {
+        let account = context.banks_client.get_account(request).await.unwrap();
+        assert!(account.is_none(), "request {} is still open", request);
+    }
+}
+
+/// The controller owner shortens how long a request stays completable; an approved
+/// request that is completed too late is rejected.
+///
+/// # Panics
+///
+/// Panics if a request is completed after it expired.
+#[tokio::test]
+async fn f03_4_spend_request_max_age() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let authorizer = Keypair::new();
+    for payer in [&fee_payer, &authorizer] {
+        airdrop(&mut context, &payer.pubkey(), 10 * 100_000_000)
+            .await
+            .unwrap();
+    }
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: authorizer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let max_request_age = 100;
+    send_tx(
+        &mut context,
+        &[dispenser.set_max_request_age_ix(max_request_age).unwrap()],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+    send_tx(
+        &mut context,
+        &[approve_spend_ix(
+            &request.pubkey(),
+            &authorizer.pubkey(),
+            &destination_owner,
+            &cb.id,
+            amount,
+        )],
+        &authorizer.pubkey(),
+        &[&authorizer],
+    )
+    .await
+    .unwrap();
+
+    let slot = context.banks_client.get_root_slot().await.unwrap();
+    context.warp_to_slot(slot + max_request_age + 1).unwrap();
+    let mut keypair_list = Vec::new();
+    let mut ix_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    match send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer]).await {
+        Ok(_) => panic!("completed an expired request"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::SpendRequestExpired));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    // the shorter age also lets the controller owner clean up sooner
+    let ix = dispenser
+        .cancel_spend_ix(&request.pubkey(), &fee_payer.pubkey(), &ctr.owner.pubkey())
+        .unwrap();
+    send_tx(
+        &mut context,
+        &[ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+}
+
+/// A sweep processed in one transaction is re-checked when the request is completed
+/// in another.
+///
+/// # Panics
+///
+/// Panics if a request completes after the destination balance the sweep saw changed.
+#[tokio::test]
+async fn f03_5_balance_drift() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleswp::Sweep::new(&destination_owner, &cb.id, 0)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    // adding the sweep rule reads the destination token account
+    cb.issue(&mut context, &fee_payer, &destination_owner, 0)
+        .await
+        .unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // create the request and process the sweep, but do not complete it yet
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    let mut keypair_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    let complete_ix = ix_list.pop().unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+
+    cb.issue(&mut context, &fee_payer, &destination_owner, 1)
+        .await
+        .unwrap();
+    match send_tx(
+        &mut context,
+        &[complete_ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    {
+        Ok(_) => panic!("completed after the destination balance changed"),
+        Err(err) => {
+            let code = format!(
+                "{:#x}",
+                u32::from(TreasuryError::SpendRequestBalanceChanged)
+            );
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+/// A request at a PDA needs no extra signer, so a program could make it by CPI.
+///
+/// # Panics
+///
+/// Panics if the same request key can be used for two open requests.
+#[tokio::test]
+async fn f03_6_pda_spend_request() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: fee_payer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let idempotency_key = [7u8; 32];
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create_pda(
+            &mut ix_list,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+            idempotency_key,
+        )
+        .unwrap();
+    assert_eq!(request, spend_request_id(&delegation_id, &idempotency_key));
+    send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+
+    let mut ix_list = Vec::new();
+    dispenser
+        .spend_create_pda(
+            &mut ix_list,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+            idempotency_key,
+        )
+        .unwrap();
+    if send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .is_ok()
+    {
+        panic!("opened a second request with the same key");
+    }
+
+    let mut keypair_list = vec![fee_payer.insecure_clone()];
+    let mut ix_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+    let account = context.banks_client.get_account(request).await.unwrap();
+    assert!(account.is_none(), "request {} is still open", request);
+}
+
+/// An invoice paid with an idempotency key cannot be paid again by a retry.
+///
+/// # Panics
+///
+/// Panics if a key can be reused after its request has completed.
+#[tokio::test]
+async fn f03_7_idempotency_key() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: fee_payer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let invoice = [9u8; 32];
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create_pda(
+            &mut ix_list,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+            invoice,
+        )
+        .unwrap();
+    let mut keypair_list = vec![fee_payer.insecure_clone()];
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+
+    let account = context
+        .banks_client
+        .get_account(spend_receipt_id(&delegation_id, &invoice))
+        .await
+        .unwrap()
+        .unwrap();
+    let receipt = SpendReceipt::try_deserialize(&mut account.data.as_slice()).unwrap();
+    assert_eq!(receipt.request, request);
+    assert_eq!(receipt.amount, amount / 2);
+
+    // the request account is gone, but the receipt still blocks the key
+    let mut ix_list = Vec::new();
+    dispenser
+        .spend_create_pda(
+            &mut ix_list,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+            invoice,
+        )
+        .unwrap();
+    if send_tx(&mut context, &ix_list, &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .is_ok()
+    {
+        panic!("paid the same invoice twice");
+    }
+}
+
+/// A time window processed in one transaction is checked again when the request is
+/// completed in another.
+///
+/// # Panics
+///
+/// Panics if a request completes after its time window ended.
+#[tokio::test]
+async fn f03_8_time_window_rechecked() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let mut clock: Clock = context.banks_client.get_sysvar().await.unwrap();
+    let valid_until = clock.unix_timestamp + 3_600;
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruletw::TimeWindow::new(0, valid_until)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // inside the window, a request completed in a later transaction still pays
+    let destination_owner = Keypair::new().pubkey();
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount / 2,
+    )
+    .await
+    .unwrap();
+
+    // process the rule just before the window ends, complete it just after
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(
+            &mut ix_list,
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+            amount / 2,
+        )
+        .unwrap();
+    let mut keypair_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    let complete_ix = ix_list.pop().unwrap();
+    clock.unix_timestamp = valid_until - 1;
+    context.set_sysvar(&clock);
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+
+    clock.unix_timestamp = valid_until;
+    context.set_sysvar(&clock);
+    match send_tx(
+        &mut context,
+        &[complete_ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    {
+        Ok(_) => panic!("completed after the time window ended"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+/// Two requests that each pass a cap on their own cannot both complete
+/// when together they go over it.
+///
+/// # Panics
+///
+/// Panics if the second request completes.
+#[tokio::test]
+async fn f03_9_caps_rechecked() {
+    f03_9_complete_two_requests(|mint, cap| Box::new(rulebc::BudgetCap::new(mint, cap))).await;
+    f03_9_complete_two_requests(|mint, cap| {
+        Box::new(rulerl::RateLimiter {
+            x: RateLimiter::new(mint, cap, 1_000).unwrap(),
+        })
+    })
+    .await;
+}
+
+async fn f03_9_complete_two_requests(
+    make_rule: fn(&Pubkey, u64) -> Box<dyn DispenserRule<'static>>,
+) {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let cap: u64 = 1_000_000;
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser.rule_add2(make_rule(&cb.id, cap)).unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    // enough that the balance is not what stops the second request
+    cb.issue(&mut context, &fee_payer, &ctr.id, 2 * cap)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        2 * cap,
+    )
+    .await
+    .unwrap();
+
+    // process both requests before either completes
+    let destination_owner = Keypair::new().pubkey();
+    let mut complete_list = Vec::new();
+    for _ in 0..2 {
+        let mut ix_list = Vec::new();
+        let request = dispenser
+            .spend_create(
+                &mut ix_list,
+                &fee_payer,
+                &destination_owner,
+                &cb.id,
+                cap / 2 + 1,
+            )
+            .unwrap();
+        let mut keypair_list = Vec::new();
+        dispenser
+            .spend_finish(
+                &mut keypair_list,
+                &mut ix_list,
+                &request.pubkey(),
+                &fee_payer,
+                &destination_owner,
+                &cb.id,
+            )
+            .unwrap();
+        complete_list.push(ix_list.pop().unwrap());
+        send_tx(
+            &mut context,
+            &ix_list,
+            &fee_payer.pubkey(),
+            &[&fee_payer, &request],
+        )
+        .await
+        .unwrap();
+    }
+
+    let second = complete_list.pop().unwrap();
+    send_tx(
+        &mut context,
+        &complete_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    .unwrap();
+    match send_tx(&mut context, &[second], &fee_payer.pubkey(), &[&fee_payer]).await {
+        Ok(_) => panic!("completed a request that goes over the cap"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+/// The linker can still cancel its request once the delegation is closed.
+///
+/// # Panics
+///
+/// Panics if the request stays open after the linker cancels it.
+#[tokio::test]
+async fn f03_10_cancel_after_close() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    let leaf = Rc::new(RefCell::new(Node::new()));
+    leaf.borrow_mut().set_i(0);
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(leaf))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: Keypair::new().pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    let amount: u64 = 1_000_000;
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let destination_owner = Keypair::new().pubkey();
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+
+    let ix = dispenser.close_ix(&fee_payer.pubkey()).unwrap();
+    send_tx(
+        &mut context,
+        &[ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+    let account = context
+        .banks_client
+        .get_account(delegation_id)
+        .await
+        .unwrap();
+    assert!(account.is_none(), "delegation is still open");
+
+    let ix = dispenser
+        .cancel_spend_ix(&request.pubkey(), &fee_payer.pubkey(), &fee_payer.pubkey())
+        .unwrap();
+    send_tx(&mut context, &[ix], &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .unwrap();
+    let account = context
+        .banks_client
+        .get_account(request.pubkey())
+        .await
+        .unwrap();
+    assert!(account.is_none(), "request is still open");
+}
+
+/// Only the balances of leaves that passed are checked again at completion.
+///
+/// # Panics
+///
+/// Panics if the balance seen by a failed sweep leaf blocks an amount leaf that passed.
+#[tokio::test]
+async fn f03_11_failed_leaf_balance() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    airdrop(&mut context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    let ctr = ControllerCreator::new_from_context(&mut context, &fee_payer)
+        .await
+        .unwrap();
+
+    // sweep OR amount; the empty destination fails the sweep
+    let destination_owner = Keypair::new().pubkey();
+    let amount: u64 = 1_000_000;
+    let sweep = Rc::new(RefCell::new(Node::new()));
+    sweep.borrow_mut().set_i(0);
+    let small = Rc::new(RefCell::new(Node::new()));
+    small.borrow_mut().set_i(1);
+    let root = Rc::new(RefCell::new(Node::new_with_children(false, &sweep, &small)));
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 2, &serialize(Some(root))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleswp::Sweep::new(&destination_owner, &cb.id, 1)))
+        .unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleamt::AmountConstraint::new(&cb.id, amount)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    cb.issue(&mut context, &fee_payer, &destination_owner, 0)
+        .await
+        .unwrap();
+    do_delegation(&mut context, &fee_payer, &ctr, &dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    cb.issue(&mut context, &fee_payer, &ctr.id, amount)
+        .await
+        .unwrap();
+    ctr.transfer(
+        &mut context,
+        true,
+        &fee_payer,
+        &cb.id,
+        &delegation_id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    let mut keypair_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    let complete_ix = ix_list.pop().unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+
+    cb.issue(&mut context, &fee_payer, &destination_owner, 1)
+        .await
+        .unwrap();
+    send_tx(
+        &mut context,
+        &[complete_ix],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    .unwrap();
+}
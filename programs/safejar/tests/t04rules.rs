@@ -0,0 +1,1168 @@
+//#![cfg(feature = "test-sbf")]
+
+use std::{cell::RefCell, rc::Rc};
+
+use safejar::{
+    self,
+    errors::TreasuryError,
+    ruleauthconstr::AuthorizationConstraintOnly,
+    sigverify::spend_intent_message,
+    spend::TREE_MAX_SIZE,
+    tree::{
+        deserialize, evaluate, result_size, serialize, set_result, validate, BooleanTreeError,
+        Node, NULL, TREE_MAX_DEPTH, TREE_MAX_LEAVES, TREE_V1,
+    },
+};
+use solana_program_test::{tokio, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    compute_budget::{self, ComputeBudgetInstruction},
+    pubkey::Pubkey,
+    signature::Keypair,
+    signer::Signer,
+};
+
+pub mod common;
+use common::{
+    basic::{airdrop, send_tx},
+    centralbank::CentralBank,
+    controller::ControllerCreator,
+    dispenser::{do_delegation, do_spend, do_spend_direct, Dispenser},
+    rpc::token_balance,
+    ruleab, ruleac, ruleal, ruleamt, rulebc, rulecp, rulems, rulepc, rulesig, ruletw,
+};
+
+/// Small payments need no co-signer: amount constraint OR authorization constraint.
+///
+/// # Panics
+///
+/// Panics if a large payment goes through without the authorizer.
+#[tokio::test]
+async fn f04_1_amount_or_authorizer() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let small: u64 = 1_000;
+    let authorizer = Keypair::new();
+    let mut dispenser = Dispenser::new(
+        &ctr.owner.pubkey(),
+        1,
+        &serialize(Some(make_tree(false, 2))),
+    )
+    .unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleamt::AmountConstraint::new(&cb.id, small)))
+        .unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: authorizer.pubkey(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    let delegation_id = fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 100 * small).await;
+
+    let destination_owner = Keypair::new();
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        small,
+    )
+    .await
+    .unwrap();
+
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        small + 1,
+    )
+    .await
+    .is_ok()
+    {
+        panic!(
+            "large spend from {} went through without the authorizer",
+            delegation_id
+        );
+    }
+
+    let mut keypair_list = vec![authorizer.insecure_clone()];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner.pubkey(),
+        &cb.id,
+        small + 1,
+    )
+    .await
+    .unwrap();
+}
+
+/// A budget cap stops spending for good once the total is reached,
+/// and a time window that has not started blocks everything.
+///
+/// # Panics
+///
+/// Panics if the budget can be exceeded.
+#[tokio::test]
+async fn f04_2_budget_cap_and_time_window() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let budget: u64 = 10_000;
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 2)))).unwrap();
+    dispenser
+        .rule_add2(Box::new(rulebc::BudgetCap::new(&cb.id, budget)))
+        .unwrap();
+    dispenser
+        .rule_add2(Box::new(ruletw::TimeWindow::new(0, i64::MAX)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 2 * budget).await;
+
+    let destination_owner = Pubkey::new_unique();
+    for _ in 0..2 {
+        let mut keypair_list = Vec::new();
+        do_spend(
+            &mut context,
+            &mut keypair_list,
+            &fee_payer,
+            &dispenser,
+            &destination_owner,
+            &cb.id,
+            budget / 2,
+        )
+        .await
+        .unwrap();
+    }
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        1,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("spent past the budget cap");
+    }
+
+    // a window far in the future
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruletw::TimeWindow::new(i64::MAX - 1, i64::MAX)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, budget).await;
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        1,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("spent before the time window started");
+    }
+}
+
+/// Payroll: one allowlist leaf covers every employee.
+///
+/// # Panics
+///
+/// Panics if a destination outside the allowlist gets paid.
+#[tokio::test]
+async fn f04_3_destination_allowlist() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let employee_list: Vec<Pubkey> = (0..300).map(|_| Pubkey::new_unique()).collect();
+    let allowlist = ruleal::DestinationAllowlist::new(&cb.id, &employee_list);
+    let target = allowlist.target.clone();
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser.rule_add2(Box::new(allowlist)).unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+
+    for i in [0, 7, 299] {
+        target.set(employee_list[i]);
+        let mut keypair_list = Vec::new();
+        do_spend(
+            &mut context,
+            &mut keypair_list,
+            &fee_payer,
+            &dispenser,
+            &employee_list[i],
+            &cb.id,
+            amount,
+        )
+        .await
+        .unwrap();
+    }
+
+    let stranger = Pubkey::new_unique();
+    target.set(stranger);
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &stranger,
+        &cb.id,
+        amount,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("paid a destination outside the allowlist");
+    }
+}
+
+/// Payees in an address book can change without re-creating the delegation.
+///
+/// # Panics
+///
+/// Panics if a blocked or unlisted destination gets paid.
+#[tokio::test]
+async fn f04_4_address_book() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let employee = Pubkey::new_unique();
+    let contractor = Pubkey::new_unique();
+    let book = ruleab::AddressBook::new(&ctr.owner.pubkey(), 0);
+    send_tx(
+        &mut context,
+        &[
+            book.create_ix(&fee_payer.pubkey(), 10),
+            book.add_ix(&employee, true),
+            book.add_ix(&contractor, false),
+        ],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+
+    let rule = ruleab::AddressBookConstraint::new(&book.id, &cb.id);
+    let target = rule.target.clone();
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser.rule_add2(Box::new(rule)).unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+
+    target.set(employee);
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &employee,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    target.set(contractor);
+    let mut keypair_list = Vec::new();
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &contractor,
+        &cb.id,
+        amount,
+    )
+    .await
+    {
+        Ok(_) => panic!("paid a blocked destination"),
+        // a failed leaf only shows up when the tree is evaluated
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    // unblock the contractor and drop the employee; the delegation stays the same
+    send_tx(
+        &mut context,
+        &[book.add_ix(&contractor, true), book.remove_ix(&employee)],
+        &fee_payer.pubkey(),
+        &[&fee_payer, &ctr.owner],
+    )
+    .await
+    .unwrap();
+
+    target.set(contractor);
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &contractor,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    target.set(employee);
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &employee,
+        &cb.id,
+        amount,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("paid a destination removed from the address book");
+    }
+}
+
+/// 2-of-3 in a single leaf.
+///
+/// # Panics
+///
+/// Panics if one signer is enough.
+#[tokio::test]
+async fn f04_5_multisig() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let signer_list: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+    let authorizer_list: Vec<Pubkey> = signer_list.iter().map(|kp| kp.pubkey()).collect();
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser
+        .rule_add2(Box::new(rulems::MultisigConstraint::new(
+            2,
+            &authorizer_list,
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+    let destination_owner = Pubkey::new_unique();
+
+    // the same signer twice still counts once
+    let mut keypair_list = vec![
+        signer_list[1].insecure_clone(),
+        signer_list[1].insecure_clone(),
+    ];
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    {
+        Ok(_) => panic!("spent with one of three signers"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    let mut keypair_list = vec![
+        signer_list[0].insecure_clone(),
+        signer_list[2].insecure_clone(),
+    ];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+}
+
+/// A cold key signs spend intents off chain and a relayer submits them.
+///
+/// # Panics
+///
+/// Panics if a signed intent can be used twice.
+#[tokio::test]
+async fn f04_6_ed25519_intent() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let relayer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &relayer).await;
+
+    let cold_key = Keypair::new();
+    let rule = rulesig::Ed25519Signature::new(&cold_key);
+    let intent = rule.intent.clone();
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser.rule_add2(Box::new(rule)).unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    let delegation_id = fund(&mut context, &relayer, &ctr, &cb, &dispenser, 10 * amount).await;
+
+    let destination_owner = Pubkey::new_unique();
+    let destination_vault =
+        anchor_spl::associated_token::get_associated_token_address(&destination_owner, &cb.id);
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, amount, 1);
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &relayer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // replaying the first intent fails because the nonce has moved on
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &relayer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("replayed a signed intent");
+    }
+
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, amount, 2);
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &relayer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // two requests processed before either completes both see nonce 3,
+    // so one signed intent would otherwise pay twice
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, amount, 3);
+    let mut complete_list = Vec::new();
+    for _ in 0..2 {
+        let mut ix_list = Vec::new();
+        let request = dispenser
+            .spend_create(&mut ix_list, &relayer, &destination_owner, &cb.id, amount)
+            .unwrap();
+        let mut keypair_list = Vec::new();
+        dispenser
+            .spend_finish(
+                &mut keypair_list,
+                &mut ix_list,
+                &request.pubkey(),
+                &relayer,
+                &destination_owner,
+                &cb.id,
+            )
+            .unwrap();
+        complete_list.push(ix_list.pop().unwrap());
+        send_tx(
+            &mut context,
+            &ix_list,
+            &relayer.pubkey(),
+            &[&relayer, &request],
+        )
+        .await
+        .unwrap();
+    }
+    let second = complete_list.pop().unwrap();
+    send_tx(&mut context, &complete_list, &relayer.pubkey(), &[&relayer])
+        .await
+        .unwrap();
+    match send_tx(&mut context, &[second], &relayer.pubkey(), &[&relayer]).await {
+        Ok(_) => panic!("one signed intent paid twice"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+/// An EVM key sits next to a normal authorizer: Secp256k1 signature OR authorization constraint.
+///
+/// # Panics
+///
+/// Panics if an intent signed for another amount is accepted.
+#[tokio::test]
+async fn f04_7_secp256k1_intent() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    // do_spend sends the create instruction first, then the precompile
+    let rule = rulesig::Secp256k1Signature::new(1);
+    let intent = rule.intent.clone();
+    let mut dispenser = Dispenser::new(
+        &ctr.owner.pubkey(),
+        1,
+        &serialize(Some(make_tree(false, 2))),
+    )
+    .unwrap();
+    dispenser.rule_add2(Box::new(rule)).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+            AuthorizationConstraintOnly {
+                required_authorizer: Pubkey::new_unique(),
+            },
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    let delegation_id = fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+
+    let destination_owner = Pubkey::new_unique();
+    let destination_vault =
+        anchor_spl::associated_token::get_associated_token_address(&destination_owner, &cb.id);
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, 2 * amount, 1);
+    let mut keypair_list = Vec::new();
+    if do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .is_ok()
+    {
+        panic!("accepted an intent for another amount");
+    }
+
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, amount, 1);
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+
+    // one intent, two requests processed before either completes
+    *intent.borrow_mut() =
+        spend_intent_message(&delegation_id, &destination_vault, &cb.id, amount, 2);
+    let mut complete_list = Vec::new();
+    for _ in 0..2 {
+        let mut ix_list = Vec::new();
+        let request = dispenser
+            .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+            .unwrap();
+        let mut keypair_list = Vec::new();
+        dispenser
+            .spend_finish(
+                &mut keypair_list,
+                &mut ix_list,
+                &request.pubkey(),
+                &fee_payer,
+                &destination_owner,
+                &cb.id,
+            )
+            .unwrap();
+        complete_list.push(ix_list.pop().unwrap());
+        send_tx(
+            &mut context,
+            &ix_list,
+            &fee_payer.pubkey(),
+            &[&fee_payer, &request],
+        )
+        .await
+        .unwrap();
+    }
+    let second = complete_list.pop().unwrap();
+    send_tx(
+        &mut context,
+        &complete_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    .unwrap();
+    if send_tx(&mut context, &[second], &fee_payer.pubkey(), &[&fee_payer])
+        .await
+        .is_ok()
+    {
+        panic!("one signed intent paid twice");
+    }
+}
+
+/// Petty cash is paid some other way; anything above it needs 2 of 3 officers.
+/// The tree is AND(NOT(amount constraint), 2-of-3(authorizer, authorizer, authorizer)).
+///
+/// # Panics
+///
+/// Panics if petty cash goes through or one officer is enough.
+#[tokio::test]
+async fn f04_8_not_and_threshold_gates() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let petty: u64 = 1_000;
+    let officer_list: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+    let leaf_list: Vec<Rc<RefCell<Node>>> = (0..4)
+        .map(|i| {
+            let leaf = Rc::new(RefCell::new(Node::new()));
+            leaf.borrow_mut().set_i(i);
+            leaf
+        })
+        .collect();
+    let not_petty = Rc::new(RefCell::new(Node::new_not(&leaf_list[0])));
+    let officers = Rc::new(RefCell::new(Node::new_threshold(2, &leaf_list[1..])));
+    let root = Rc::new(RefCell::new(Node::new_with_children(
+        true, &not_petty, &officers,
+    )));
+    let tree = serialize(Some(root));
+    assert_eq!(tree[0], TREE_V1);
+    let (_root, count) = deserialize(&tree).unwrap();
+    assert_eq!(count, 4);
+
+    let mut dispenser = Dispenser::new(&ctr.owner.pubkey(), 1, &tree).unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleamt::AmountConstraint::new(&cb.id, petty)))
+        .unwrap();
+    for officer in officer_list.iter() {
+        dispenser
+            .rule_add2(Box::new(ruleac::AuthorizationConstraint::new(
+                AuthorizationConstraintOnly {
+                    required_authorizer: officer.pubkey(),
+                },
+            )))
+            .unwrap();
+    }
+    dispenser.rule_stop().unwrap();
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 100 * petty).await;
+    let destination_owner = Pubkey::new_unique();
+
+    let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+    for (amount, signer_count) in [(petty, 3), (petty + 1, 1)] {
+        let mut keypair_list: Vec<Keypair> = officer_list[..signer_count]
+            .iter()
+            .map(|kp| kp.insecure_clone())
+            .collect();
+        match do_spend(
+            &mut context,
+            &mut keypair_list,
+            &fee_payer,
+            &dispenser,
+            &destination_owner,
+            &cb.id,
+            amount,
+        )
+        .await
+        {
+            Ok(_) => panic!("spent {} with {} officers", amount, signer_count),
+            Err(err) => assert!(err.to_string().contains(&code), "wrong error: {}", err),
+        }
+    }
+
+    let mut keypair_list = vec![
+        officer_list[0].insecure_clone(),
+        officer_list[2].insecure_clone(),
+    ];
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        petty + 1,
+    )
+    .await
+    .unwrap();
+}
+
+/// The on-chain evaluator walks the encoded tree with a fixed-size stack.
+///
+/// # Panics
+///
+/// Panics if a tree one gate deeper than TREE_MAX_DEPTH is accepted.
+#[test]
+fn f04_9_flat_evaluator_depth() {
+    // make_tree nests count - 1 gates
+    let depth = TREE_MAX_DEPTH as u8;
+    let deepest = serialize(Some(make_tree(true, depth + 1)));
+    assert_eq!(validate(&deepest).unwrap(), depth + 1);
+    let mut all = vec![u8::MAX; result_size(depth as usize + 1)];
+    assert!(evaluate(&deepest, &all).unwrap());
+    all[0] &= !(1 << 5);
+    assert!(!evaluate(&deepest, &all).unwrap());
+    let (_root, count) = deserialize(&deepest).unwrap();
+    assert_eq!(count, depth + 1);
+
+    let any = serialize(Some(make_tree(false, depth + 1)));
+    let mut last = vec![0; result_size(depth as usize + 1)];
+    assert!(!evaluate(&any, &last).unwrap());
+    set_result(&mut last, &depth);
+    assert!(evaluate(&any, &last).unwrap());
+
+    let too_deep = serialize(Some(make_tree(true, depth + 2)));
+    match validate(&too_deep) {
+        Err(BooleanTreeError::TooDeep) => {}
+        _ => panic!("tree deeper than {} accepted", TREE_MAX_DEPTH),
+    }
+}
+
+/// Policies that could never be satisfied, or use a reserved leaf index,
+/// are rejected before a rule accumulator is created.
+///
+/// # Panics
+///
+/// Panics if a malformed tree is accepted.
+#[test]
+fn f04_10_strict_tree_validation() {
+    let and = |left: u8, right: u8| vec![NULL, 1, left, right];
+    assert_eq!(validate(&and(1, 0)).unwrap(), 2);
+
+    let bad_list: Vec<(Vec<u8>, BooleanTreeError)> = vec![
+        (and(0, 0), BooleanTreeError::DuplicateLeaf),
+        (and(0, 2), BooleanTreeError::LeafGap),
+        (
+            and(0, TREE_MAX_LEAVES as u8),
+            BooleanTreeError::LeafOutOfRange,
+        ),
+        (vec![0; TREE_MAX_SIZE + 1], BooleanTreeError::TooLarge),
+        (vec![NULL, 1, 0], BooleanTreeError::FailedToRead),
+        (vec![0, 1], BooleanTreeError::IndexOutOfRange),
+    ];
+    for (tree, expected) in bad_list {
+        match validate(&tree) {
+            Err(err) => assert_eq!(u32::from(err), u32::from(expected), "tree {:?}", tree),
+            Ok(count) => panic!("tree {:?} accepted with {} leaves", tree, count),
+        }
+        assert!(deserialize(&tree).is_err());
+    }
+}
+
+/// A policy is no longer capped at 64 leaves; the result bitmap grows with the tree.
+///
+/// # Panics
+///
+/// Panics if leaves past 64 are dropped.
+#[test]
+fn f04_11_more_than_64_leaves() {
+    let count: u8 = 200;
+    let leaf_list: Vec<Rc<RefCell<Node>>> = (0..count)
+        .map(|i| {
+            let leaf = Rc::new(RefCell::new(Node::new()));
+            leaf.borrow_mut().set_i(i);
+            leaf
+        })
+        .collect();
+    let root = Rc::new(RefCell::new(Node::new_threshold(count - 1, &leaf_list)));
+    let tree = serialize(Some(root));
+    assert!(tree.len() <= TREE_MAX_SIZE);
+    assert_eq!(validate(&tree).unwrap(), count);
+
+    let mut result = vec![0; result_size(count as usize)];
+    assert_eq!(result.len(), 25);
+    for i in 0..count - 2 {
+        set_result(&mut result, &i);
+    }
+    assert!(!evaluate(&tree, &result).unwrap());
+    set_result(&mut result, &(count - 1));
+    assert!(evaluate(&tree, &result).unwrap());
+}
+
+/// The whole spend in one instruction: small amounts pass on their own,
+/// larger ones need 2 of 3 co-signers, and no SpendRequest account is created.
+///
+/// # Panics
+///
+/// Panics if one co-signer is enough.
+#[tokio::test]
+async fn f04_12_spend_direct() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let small: u64 = 1_000;
+    let signer_list: Vec<Keypair> = (0..3).map(|_| Keypair::new()).collect();
+    let authorizer_list: Vec<Pubkey> = signer_list.iter().map(|kp| kp.pubkey()).collect();
+    let mut dispenser = Dispenser::new(
+        &ctr.owner.pubkey(),
+        1,
+        &serialize(Some(make_tree(false, 2))),
+    )
+    .unwrap();
+    dispenser
+        .rule_add2(Box::new(ruleamt::AmountConstraint::new(&cb.id, small)))
+        .unwrap();
+    dispenser
+        .rule_add2(Box::new(rulems::MultisigConstraint::new(
+            2,
+            &authorizer_list,
+        )))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 100 * small).await;
+    let destination_owner = Pubkey::new_unique();
+
+    let mut keypair_list = Vec::new();
+    do_spend_direct(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        small,
+    )
+    .await
+    .unwrap();
+
+    let mut keypair_list = vec![signer_list[0].insecure_clone()];
+    match do_spend_direct(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        small + 1,
+    )
+    .await
+    {
+        Ok(_) => panic!("spent with one of three co-signers"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    let mut keypair_list = vec![
+        signer_list[0].insecure_clone(),
+        signer_list[1].insecure_clone(),
+    ];
+    do_spend_direct(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        small + 1,
+    )
+    .await
+    .unwrap();
+}
+
+/// A program constraint pins spends to token accounts of accounts owned by one program;
+/// here the controller PDA, which belongs to safejar.
+///
+/// # Panics
+///
+/// Panics if the spend to the controller does not arrive.
+#[tokio::test]
+async fn f04_13_program_owned_destination() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let amount: u64 = 1_000;
+    let mut dispenser = Dispenser::new(
+        &ctr.owner.pubkey(),
+        1,
+        &serialize(Some(make_tree(false, 1))),
+    )
+    .unwrap();
+    dispenser
+        .rule_add2(Box::new(rulepc::ProgramConstraint::new(&safejar::ID)))
+        .unwrap();
+    dispenser.rule_stop().unwrap();
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, amount).await;
+    assert_eq!(token_balance(&mut context, &cb.id, &ctr.id).await, 0);
+
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create_to_program(&mut ix_list, &fee_payer, &ctr.id, &cb.id, amount)
+        .unwrap();
+    let mut keypair_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &ctr.id,
+            &cb.id,
+        )
+        .unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+    assert_eq!(token_balance(&mut context, &cb.id, &ctr.id).await, amount);
+}
+
+/// Only transactions that also call a required program can spend; the linker does not matter.
+///
+/// # Panics
+///
+/// Panics if a spend goes through without the required program instruction.
+#[tokio::test]
+async fn f04_14_caller_program() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    // spend_create takes position 0, so the required instruction goes right after it
+    let rule = rulecp::CallerProgram::new(&compute_budget::ID, 1);
+    let call = rule.call.clone();
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser.rule_add2(Box::new(rule)).unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+    let destination_owner = Pubkey::new_unique();
+
+    let mut keypair_list = Vec::new();
+    match do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    {
+        Ok(_) => panic!("spent without the required program"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+
+    *call.borrow_mut() = Some(ComputeBudgetInstruction::set_compute_unit_limit(400_000));
+    let mut keypair_list = Vec::new();
+    do_spend(
+        &mut context,
+        &mut keypair_list,
+        &fee_payer,
+        &dispenser,
+        &destination_owner,
+        &cb.id,
+        amount,
+    )
+    .await
+    .unwrap();
+}
+
+/// A request whose caller program leaf passed cannot be completed in a transaction
+/// without the required program.
+///
+/// # Panics
+///
+/// Panics if the linker completes the request on its own.
+#[tokio::test]
+async fn f04_15_caller_program_complete() {
+    let mut validator = ProgramTest::default();
+    validator.add_program("safejar", safejar::ID, None);
+    let cb: CentralBank = CentralBank::new_from_validator(&mut validator).unwrap();
+    let mut context: ProgramTestContext = validator.start_with_context().await;
+    let fee_payer = Keypair::new();
+    let ctr = prepare_controller(&mut context, &fee_payer).await;
+
+    let rule = rulecp::CallerProgram::new(&compute_budget::ID, 1);
+    let call = rule.call.clone();
+    *call.borrow_mut() = Some(ComputeBudgetInstruction::set_compute_unit_limit(400_000));
+    let mut dispenser =
+        Dispenser::new(&ctr.owner.pubkey(), 1, &serialize(Some(make_tree(true, 1)))).unwrap();
+    dispenser.rule_add2(Box::new(rule)).unwrap();
+    dispenser.rule_stop().unwrap();
+    let amount: u64 = 1_000;
+    fund(&mut context, &fee_payer, &ctr, &cb, &dispenser, 10 * amount).await;
+    let destination_owner = Pubkey::new_unique();
+
+    // the leaf is processed next to the required program, then the linker completes alone
+    let mut ix_list = Vec::new();
+    let request = dispenser
+        .spend_create(&mut ix_list, &fee_payer, &destination_owner, &cb.id, amount)
+        .unwrap();
+    let mut keypair_list = Vec::new();
+    dispenser
+        .spend_finish(
+            &mut keypair_list,
+            &mut ix_list,
+            &request.pubkey(),
+            &fee_payer,
+            &destination_owner,
+            &cb.id,
+        )
+        .unwrap();
+    let complete = ix_list.pop().unwrap();
+    send_tx(
+        &mut context,
+        &ix_list,
+        &fee_payer.pubkey(),
+        &[&fee_payer, &request],
+    )
+    .await
+    .unwrap();
+    match send_tx(
+        &mut context,
+        &[complete],
+        &fee_payer.pubkey(),
+        &[&fee_payer],
+    )
+    .await
+    {
+        Ok(_) => panic!("completed without the required program"),
+        Err(err) => {
+            let code = format!("{:#x}", u32::from(TreasuryError::RuleEvalFalse));
+            assert!(err.to_string().contains(&code), "wrong error: {}", err);
+        }
+    }
+}
+
+// join leaves 0..count with AND or OR
+fn make_tree(is_and: bool, count: u8) -> Rc<RefCell<Node>> {
+    let mut root = Rc::new(RefCell::new(Node::new()));
+    root.borrow_mut().set_i(0);
+    for i in 1..count {
+        let leaf = Rc::new(RefCell::new(Node::new()));
+        leaf.borrow_mut().set_i(i);
+        root = Rc::new(RefCell::new(Node::new_with_children(is_and, &root, &leaf)));
+    }
+    root
+}
+
+async fn prepare_controller(
+    context: &mut ProgramTestContext,
+    fee_payer: &Keypair,
+) -> ControllerCreator {
+    airdrop(context, &fee_payer.pubkey(), 10 * 100_000_000)
+        .await
+        .unwrap();
+    ControllerCreator::new_from_context(context, fee_payer)
+        .await
+        .unwrap()
+}
+
+// create the delegation and move amount into it
+async fn fund<'a>(
+    context: &mut ProgramTestContext,
+    fee_payer: &Keypair,
+    ctr: &ControllerCreator,
+    cb: &CentralBank,
+    dispenser: &Dispenser<'a>,
+    amount: u64,
+) -> Pubkey {
+    do_delegation(context, fee_payer, ctr, dispenser).await;
+    let delegation_id = dispenser.delegation_id().unwrap();
+    cb.issue(context, fee_payer, &ctr.id, amount).await.unwrap();
+    ctr.transfer(context, true, fee_payer, &cb.id, &delegation_id, amount)
+        .await
+        .unwrap();
+    delegation_id
+}